@@ -1,11 +1,14 @@
 use slog::{info, Logger};
 
 mod observable_counting_semaphore;
+mod tcp;
 mod unix;
 
 pub use observable_counting_semaphore::*;
+pub use tcp::incoming_from_tcp_listener;
 pub use unix::{
     ensure_single_systemd_socket, incoming_from_first_systemd_socket, incoming_from_path,
+    incoming_from_path_with_mode,
 };
 
 /// Returns a `Future` that completes when the service should gracefully
@@ -0,0 +1,17 @@
+/// The module contains utilities used for serving RPC connections over TCP,
+/// e.g. when a process runs outside of systemd.
+use async_stream::AsyncStream;
+use futures::TryFutureExt;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Creates an incoming async stream of the connections accepted by `listener`.
+pub fn incoming_from_tcp_listener(
+    listener: TcpListener,
+) -> AsyncStream<Result<TcpStream, std::io::Error>, impl futures::Future<Output = ()>> {
+    async_stream::stream! {
+        loop {
+            let item = listener.accept().map_ok(|(stream, _)| stream).await;
+            yield item;
+        }
+    }
+}
@@ -6,7 +6,9 @@
 use async_stream::AsyncStream;
 use futures::TryFutureExt;
 use std::{
-    os::unix::io::FromRawFd,
+    fs::Permissions,
+    os::unix::{fs::PermissionsExt, io::FromRawFd},
+    path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -28,6 +30,41 @@ pub fn incoming_from_path<P: AsRef<std::path::Path>>(
     }
 }
 
+/// Like [incoming_from_path], but sets the permissions of the socket file to
+/// `mode`, if given, and fails instead of panicking if the socket cannot be
+/// created. The socket file is removed once the stream is dropped, e.g., when
+/// the server shuts down.
+pub fn incoming_from_path_with_mode<P: AsRef<Path>>(
+    path: P,
+    mode: Option<u32>,
+) -> std::io::Result<
+    AsyncStream<Result<UnixStream, std::io::Error>, impl futures::Future<Output = ()>>,
+> {
+    let path = path.as_ref();
+    let uds = tokio::net::UnixListener::bind(path)?;
+    let socket_file = SocketFile(path.to_owned());
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(async_stream::stream! {
+        let _socket_file = socket_file;
+        loop {
+            let item = uds.accept().map_ok(|(stream, _)| UnixStream(stream)).await;
+            yield item;
+        }
+    })
+}
+
+/// Removes the socket file at the wrapped path when dropped.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        // There's no way to return a useful error here.
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
 /// listener_from_first_systemd_socket() takes the first FD(3) passed by systemd. It does not check if
 /// more FDs are passed to the process. Make sure to call ensure_single_systemd_socket() before!
 /// To ensure that only one listener on the socket exists this function should only be called once!
@@ -52,22 +89,38 @@ fn listener_from_first_systemd_socket() -> tokio::net::UnixListener {
 }
 
 /// ensure_single_systemd_socket() makes sure that only one FD is received.
-pub fn ensure_single_systemd_socket() {
+/// Otherwise, it returns an error stating how many FDs were received.
+pub fn ensure_single_systemd_socket() -> Result<(), String> {
     // This env. variable is set by the systemd service manager and can be used to check what file
     // descriptors are passed.
     // Setting the env. variable is done by ic-os/guestos/rootfs/etc/systemd/system/*.socket.
     // For more info see https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
     const SYSTEMD_SOCKET_NAMES: &str = "LISTEN_FDS";
-    let systemd_sockets: u64 = std::env::var(SYSTEMD_SOCKET_NAMES)
-        .expect("failed to read number systemd sockets")
-        .parse()
-        .expect("failed to parse number of socket to integer");
-    if systemd_sockets != 1 {
-        panic!(
-            "Expected to receive a single systemd socket but instead got '{}'",
-            systemd_sockets
-        );
+    check_single_systemd_socket(std::env::var(SYSTEMD_SOCKET_NAMES).ok())
+}
+
+/// Checks that the value of `LISTEN_FDS`, if set, is exactly one.
+fn check_single_systemd_socket(listen_fds: Option<String>) -> Result<(), String> {
+    const EXPECTED_SOCKETS: u64 = 1;
+    let listen_fds = listen_fds.ok_or_else(|| {
+        format!(
+            "Expected to receive {} systemd socket but LISTEN_FDS is not set",
+            EXPECTED_SOCKETS
+        )
+    })?;
+    let systemd_sockets: u64 = listen_fds.parse().map_err(|_| {
+        format!(
+            "Failed to parse the number of systemd sockets from LISTEN_FDS='{}'",
+            listen_fds
+        )
+    })?;
+    if systemd_sockets != EXPECTED_SOCKETS {
+        return Err(format!(
+            "Expected to receive {} systemd socket but instead got {}",
+            EXPECTED_SOCKETS, systemd_sockets
+        ));
     }
+    Ok(())
 }
 
 /// Creates an incoming async stream using the first systemd socket.
@@ -131,3 +184,35 @@ impl AsyncWrite for UnixStream {
         Pin::new(&mut self.0).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_systemd_socket() {
+        assert_eq!(check_single_systemd_socket(Some("1".to_string())), Ok(()));
+    }
+
+    #[test]
+    fn test_wrong_number_of_systemd_sockets() {
+        assert_eq!(
+            check_single_systemd_socket(Some("0".to_string())),
+            Err("Expected to receive 1 systemd socket but instead got 0".to_string())
+        );
+        assert_eq!(
+            check_single_systemd_socket(Some("2".to_string())),
+            Err("Expected to receive 1 systemd socket but instead got 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_missing_or_invalid_listen_fds() {
+        assert!(check_single_systemd_socket(None)
+            .unwrap_err()
+            .contains("LISTEN_FDS is not set"));
+        assert!(check_single_systemd_socket(Some("two".to_string()))
+            .unwrap_err()
+            .contains("LISTEN_FDS='two'"));
+    }
+}
@@ -108,7 +108,9 @@ pub fn spawn_grpc_server(
 ) {
     // make sure we receive only one socket from systemd
     if config.incoming_source == IncomingSource::Systemd {
-        ensure_single_systemd_socket();
+        if let Err(err) = ensure_single_systemd_socket() {
+            panic!("{}", err);
+        }
     }
     let btc_adapter_impl = BtcAdapterImpl {
         adapter_state,
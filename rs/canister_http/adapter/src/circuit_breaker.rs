@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The minimum number of tracked hosts at which stale hosts are pruned.
+const MIN_PRUNE_LEN: usize = 64;
+
+/// Tracks the failures of the requests to each host. Once a host failed
+/// `failure_threshold` consecutive times within `window`, the circuit of the
+/// host opens and requests to it are rejected for `cooldown`. Afterwards, a
+/// single request is let through per `cooldown` to probe the host, and a
+/// failure of the probe reopens the circuit.
+///
+/// Hosts are chosen by canisters, so hosts that no longer affect requests are
+/// forgotten: closed hosts whose failures are older than `window`, and open
+/// hosts that were not probed for `window` after their cooldown.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    hosts: Mutex<Hosts>,
+}
+
+struct Hosts {
+    states: HashMap<String, HostState>,
+    /// The number of tracked hosts at which stale hosts are pruned next. It
+    /// doubles with the number of hosts left, so that pruning takes amortized
+    /// constant time per host.
+    prune_len: usize,
+}
+
+struct HostState {
+    /// The number of consecutive failures since `first_failure`.
+    failures: u32,
+    first_failure: Instant,
+    /// Set while the circuit is open or half-open, i.e. once the cooldown has
+    /// passed but no request has succeeded yet. Letting a probe through
+    /// postpones it by another cooldown.
+    open_until: Option<Instant>,
+}
+
+impl HostState {
+    /// Returns true if the state no longer affects the requests to the host.
+    fn is_stale(&self, now: Instant, window: Duration) -> bool {
+        let since = self.open_until.unwrap_or(self.first_failure);
+        now.saturating_duration_since(since) >= window
+    }
+}
+
+impl CircuitBreaker {
+    /// Creates a circuit breaker. A `failure_threshold` of zero disables it.
+    pub fn new(failure_threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            window,
+            cooldown,
+            hosts: Mutex::new(Hosts {
+                states: HashMap::new(),
+                prune_len: MIN_PRUNE_LEN,
+            }),
+        }
+    }
+
+    /// Returns `false` if the circuit of `host` is open, or if it is half-open
+    /// and another request already probes the host.
+    pub fn allow(&self, host: &str) -> bool {
+        self.allow_at(host, Instant::now())
+    }
+
+    /// Closes the circuit of `host`.
+    pub fn record_success(&self, host: &str) {
+        self.hosts.lock().unwrap().states.remove(host);
+    }
+
+    /// Records a failed request to `host`, opening its circuit if needed.
+    pub fn record_failure(&self, host: &str) {
+        self.record_failure_at(host, Instant::now())
+    }
+
+    fn allow_at(&self, host: &str, now: Instant) -> bool {
+        match self.hosts.lock().unwrap().states.get_mut(host) {
+            Some(HostState {
+                open_until: Some(open_until),
+                ..
+            }) => {
+                if now < *open_until {
+                    return false;
+                }
+                // Requests are rejected until the probe succeeds, or for
+                // another cooldown if its outcome is not recorded.
+                *open_until = now + self.cooldown;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn record_failure_at(&self, host: &str, now: Instant) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let Hosts { states, prune_len } = &mut *hosts;
+        if !states.contains_key(host) && states.len() >= *prune_len {
+            let window = self.window;
+            states.retain(|_, state| !state.is_stale(now, window));
+            *prune_len = (2 * states.len()).max(MIN_PRUNE_LEN);
+        }
+        let state = states.entry(host.to_string()).or_insert(HostState {
+            failures: 0,
+            first_failure: now,
+            open_until: None,
+        });
+        if state.open_until.is_some() {
+            // The probe let through after the cooldown failed as well.
+            state.open_until = Some(now + self.cooldown);
+            return;
+        }
+        if now.saturating_duration_since(state.first_failure) >= self.window {
+            state.failures = 0;
+            state.first_failure = now;
+        }
+        state.failures += 1;
+        if state.failures >= self.failure_threshold {
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "example.com:443";
+
+    #[test]
+    fn test_opens_after_consecutive_failures_and_recovers() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10), Duration::from_secs(5));
+        let now = Instant::now();
+        for i in 0..3 {
+            assert!(breaker.allow_at(HOST, now + Duration::from_secs(i)));
+            breaker.record_failure_at(HOST, now + Duration::from_secs(i));
+        }
+        assert!(!breaker.allow_at(HOST, now + Duration::from_secs(3)));
+        assert!(breaker.allow_at("other.com:443", now + Duration::from_secs(3)));
+
+        // Once the cooldown passed, a single failure reopens the circuit.
+        assert!(breaker.allow_at(HOST, now + Duration::from_secs(7)));
+        assert!(!breaker.allow_at(HOST, now + Duration::from_secs(7)));
+        breaker.record_failure_at(HOST, now + Duration::from_secs(7));
+        assert!(!breaker.allow_at(HOST, now + Duration::from_secs(8)));
+
+        // A success closes it.
+        assert!(breaker.allow_at(HOST, now + Duration::from_secs(12)));
+        breaker.record_success(HOST);
+        breaker.record_failure_at(HOST, now + Duration::from_secs(12));
+        assert!(breaker.allow_at(HOST, now + Duration::from_secs(13)));
+    }
+
+    #[test]
+    fn test_failures_outside_window_are_not_counted() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10), Duration::from_secs(5));
+        let now = Instant::now();
+        breaker.record_failure_at(HOST, now);
+        breaker.record_failure_at(HOST, now + Duration::from_secs(10));
+        assert!(breaker.allow_at(HOST, now + Duration::from_secs(11)));
+        breaker.record_failure_at(HOST, now + Duration::from_secs(11));
+        assert!(!breaker.allow_at(HOST, now + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn test_half_open_circuit_lets_one_probe_through() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10), Duration::from_secs(5));
+        let now = Instant::now();
+        breaker.record_failure_at(HOST, now);
+        assert!(!breaker.allow_at(HOST, now + Duration::from_secs(1)));
+
+        let half_open = now + Duration::from_secs(5);
+        assert!(breaker.allow_at(HOST, half_open));
+        assert!(!breaker.allow_at(HOST, half_open));
+        assert!(!breaker.allow_at(HOST, half_open + Duration::from_secs(4)));
+        // The outcome of the probe was never recorded, so another one is let
+        // through after the cooldown.
+        assert!(breaker.allow_at(HOST, half_open + Duration::from_secs(5)));
+        breaker.record_success(HOST);
+        assert!(breaker.allow_at(HOST, half_open + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_stale_hosts_are_pruned() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10), Duration::from_secs(5));
+        let now = Instant::now();
+        // Open until 5 seconds and stale from 15 seconds on.
+        for i in 1..MIN_PRUNE_LEN {
+            breaker.record_failure_at(&format!("host-{}.com:443", i), now);
+        }
+        // Open until 15 seconds and stale from 25 seconds on.
+        breaker.record_failure_at("host-0.com:443", now + Duration::from_secs(10));
+        assert_eq!(breaker.hosts.lock().unwrap().states.len(), MIN_PRUNE_LEN);
+
+        breaker.record_failure_at(HOST, now + Duration::from_secs(15));
+        let hosts = breaker.hosts.lock().unwrap();
+        assert_eq!(hosts.states.len(), 2);
+        assert!(hosts.states.contains_key("host-0.com:443"));
+        assert!(hosts.states.contains_key(HOST));
+    }
+
+    #[test]
+    fn test_zero_threshold_disables_breaker() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(10), Duration::from_secs(5));
+        let now = Instant::now();
+        for _ in 0..10 {
+            breaker.record_failure_at(HOST, now);
+        }
+        assert!(breaker.allow_at(HOST, now));
+    }
+}
@@ -4,16 +4,39 @@
 
 use crate::config::Config;
 use clap::{AppSettings, Clap};
+use serde::Serialize;
+use serde_json::Value;
 use slog::Level;
-use std::{fs::File, io, path::PathBuf};
+use std::{
+    convert::Infallible,
+    fmt::Debug,
+    fs::File,
+    io,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::PathBuf,
+    str::FromStr,
+};
 use thiserror::Error;
 
+/// The prefix of the environment variables overriding config values.
+const ENV_PREFIX: &str = "IC_HTTP_ADAPTER_";
+
 #[derive(Debug, Error)]
 pub enum CliError {
     #[error("{0}")]
     Io(io::Error),
     #[error("An error occurred while deserialized the provided configuration: {0}")]
     Deserialize(String),
+    #[error("The configured local address {0} is not assignable: {1}")]
+    LocalAddress(IpAddr, io::Error),
+    #[error("The configured injected headers are invalid: {0}")]
+    InjectedHeaders(String),
+    #[error("The configured user agent is invalid: {0}")]
+    UserAgent(String),
+    #[error("The configured minimum TLS version is invalid: {0}")]
+    MinTlsVersion(String),
+    #[error("The configured client identity is invalid: {0}")]
+    ClientIdentity(String),
 }
 
 /// This struct is use to provide a command line interface to the adapter.
@@ -27,12 +50,65 @@ pub struct Cli {
     #[clap(short, long)]
     /// This field represents if the adapter should run in verbose.
     pub verbose: bool,
+
+    #[clap(long)]
+    /// If set, the adapter only validates the config file, prints it and exits.
+    pub check_config: bool,
+
+    #[clap(long)]
+    /// If set, the adapter listens on this TCP address or unix domain socket
+    /// path instead of the configured incoming source, e.g., to run it outside
+    /// of systemd.
+    pub listen: Option<ListenAddress>,
+
+    #[clap(long, parse(try_from_str = parse_log_level))]
+    /// If set, overrides the log level of the config file. One of `trace`,
+    /// `debug`, `info`, `warn` or `error`.
+    pub log_level: Option<Level>,
+}
+
+/// Parses the level given with `--log-level`.
+fn parse_log_level(s: &str) -> Result<Level, String> {
+    match s {
+        "trace" => Ok(Level::Trace),
+        "debug" => Ok(Level::Debug),
+        "info" => Ok(Level::Info),
+        "warn" => Ok(Level::Warning),
+        "error" => Ok(Level::Error),
+        _ => Err(format!(
+            "invalid log level '{}', expected one of trace, debug, info, warn, error",
+            s
+        )),
+    }
+}
+
+/// The address given with `--listen`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Path(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = Infallible;
+
+    /// Parses a socket address like `127.0.0.1:8080` as a TCP address and
+    /// anything else as a path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(addr) => ListenAddress::Tcp(addr),
+            Err(_) => ListenAddress::Path(PathBuf::from(s)),
+        })
+    }
 }
 
 impl Cli {
-    /// Gets the log filter level by checking the verbose field.
+    /// Gets the log filter level by checking the `log_level` and verbose
+    /// fields.
     pub fn get_logging_level(&self) -> Level {
-        if self.verbose {
+        if let Some(level) = self.log_level {
+            level
+        } else if self.verbose {
             Level::Debug
         } else {
             Level::Info
@@ -40,17 +116,91 @@ impl Cli {
     }
 
     /// Loads the config from the provided `config` argument.
+    ///
+    /// Top-level values can be overridden with environment variables named
+    /// after the field with the `IC_HTTP_ADAPTER_` prefix, e.g.,
+    /// `IC_HTTP_ADAPTER_HTTP_REQUEST_TIMEOUT_SECS=5`. Values are parsed as
+    /// JSON, falling back to a plain string if the field does not accept the
+    /// JSON value, e.g., `IC_HTTP_ADAPTER_MIN_TLS_VERSION=1.2`. The precedence
+    /// is environment variable, then config file, then default value. The log
+    /// level given with `--log-level` takes precedence over all of them.
     pub fn get_config(&self) -> Result<Config, CliError> {
+        self.get_config_with_env(std::env::vars())
+    }
+
+    fn get_config_with_env(
+        &self,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> Result<Config, CliError> {
         // The expected JSON config.
         let file = File::open(&self.config).map_err(CliError::Io)?;
-        serde_json::from_reader(file).map_err(|err| CliError::Deserialize(err.to_string()))
+        let mut value: Value =
+            serde_json::from_reader(file).map_err(|err| CliError::Deserialize(err.to_string()))?;
+        for (name, raw) in vars {
+            if let Some(field) = name.strip_prefix(ENV_PREFIX) {
+                let field = field.to_ascii_lowercase();
+                let typed =
+                    serde_json::from_str(&raw).unwrap_or_else(|_| Value::String(raw.clone()));
+                let string = Value::String(raw);
+                // JSON values such as `1.2` may be meant as strings.
+                let parsed = if typed == string
+                    || deserializes_with(&value, &field, typed.clone())
+                    || !deserializes_with(&value, &field, string.clone())
+                {
+                    typed
+                } else {
+                    string
+                };
+                if let Value::Object(fields) = &mut value {
+                    fields.insert(field, parsed);
+                }
+            }
+        }
+        let mut config: Config =
+            serde_json::from_value(value).map_err(|err| CliError::Deserialize(err.to_string()))?;
+        if let Some(level) = self.log_level {
+            config.logger.level = level;
+        }
+        if let Some(local_address) = config.local_address {
+            // Binding fails if the address does not belong to this host.
+            TcpListener::bind((local_address, 0))
+                .map_err(|err| CliError::LocalAddress(local_address, err))?;
+        }
+        config
+            .injected_header_map()
+            .map_err(CliError::InjectedHeaders)?;
+        config.user_agent_header().map_err(CliError::UserAgent)?;
+        config.min_tls_protocol().map_err(CliError::MinTlsVersion)?;
+        config.client_identity().map_err(CliError::ClientIdentity)?;
+        Ok(config)
+    }
+}
+
+/// Returns whether `config` is a valid config once `field` is set to `value`.
+fn deserializes_with(config: &Value, field: &str, value: Value) -> bool {
+    let mut config = config.clone();
+    if let Value::Object(fields) = &mut config {
+        fields.insert(field.to_string(), value);
     }
+    serde_json::from_value::<Config>(config).is_ok()
+}
+
+/// Formats `config` as pretty-printed JSON for logging. Serializing an already
+/// parsed config is not expected to fail, but if it does, the debug
+/// representation is returned instead of aborting the startup.
+pub fn config_to_string<T: Serialize + Debug>(config: &T) -> String {
+    serde_json::to_string_pretty(config).unwrap_or_else(|err| {
+        format!(
+            "{:?} (failed to serialize the config as JSON: {})",
+            config, err
+        )
+    })
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use crate::IncomingSource;
+    use crate::{IncomingSource, IpFamily};
     use std::io::Write;
     use std::path::PathBuf;
     use std::str::FromStr;
@@ -62,6 +212,9 @@ pub mod test {
         let cli = Cli {
             config: PathBuf::new(),
             verbose: false,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
 
         assert_eq!(cli.get_logging_level(), Level::Info);
@@ -69,17 +222,63 @@ pub mod test {
         let cli = Cli {
             config: PathBuf::new(),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
 
         assert_eq!(cli.get_logging_level(), Level::Debug);
     }
 
+    // This function tests parsing the command line with the `--check-config` flag.
+    #[test]
+    fn test_cli_parse_check_config() {
+        let cli = Cli::try_parse_from(vec!["adapter", "--check-config", "/tmp/config.json"])
+            .expect("Failed to parse the command line");
+        assert!(cli.check_config);
+        assert_eq!(cli.config, PathBuf::from("/tmp/config.json"));
+
+        let cli = Cli::try_parse_from(vec!["adapter", "/tmp/config.json"])
+            .expect("Failed to parse the command line");
+        assert!(!cli.check_config);
+    }
+
+    // This function tests that `--log-level` overrides the level of the config file.
+    #[test]
+    fn test_cli_log_level_overrides_config() {
+        let json = r#"{
+            "logger": {
+                "level": "info"
+            }
+        }"#;
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+        let path = tmpfile.path().to_str().unwrap();
+
+        let cli = Cli::try_parse_from(vec!["adapter", path]).unwrap();
+        assert_eq!(cli.get_config().unwrap().logger.level, Level::Info);
+
+        let cli = Cli::try_parse_from(vec!["adapter", "--log-level", "trace", path]).unwrap();
+        assert_eq!(cli.log_level, Some(Level::Trace));
+        assert_eq!(cli.get_logging_level(), Level::Trace);
+        assert_eq!(cli.get_config().unwrap().logger.level, Level::Trace);
+
+        let cli = Cli::try_parse_from(vec!["adapter", "--log-level", "warn", "-v", path]).unwrap();
+        assert_eq!(cli.get_logging_level(), Level::Warning);
+        assert_eq!(cli.get_config().unwrap().logger.level, Level::Warning);
+
+        assert!(Cli::try_parse_from(vec!["adapter", "--log-level", "loud", path]).is_err());
+    }
+
     // This function tests opening a config file that does not exist.
     #[test]
     fn test_cli_get_config_error_opening_file() {
         let cli = Cli {
             config: PathBuf::from_str("/tmp/http-adapter-test.json").expect("Bad file path string"),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
         let result = cli.get_config();
         assert!(result.is_err());
@@ -99,6 +298,9 @@ pub mod test {
         let cli = Cli {
             config: tmpfile.path().to_owned(),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
         let result = cli.get_config();
         assert!(result.is_err());
@@ -122,6 +324,9 @@ pub mod test {
         let cli = Cli {
             config: tmpfile.path().to_owned(),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
         let result = cli.get_config();
         let config = result.unwrap();
@@ -142,6 +347,9 @@ pub mod test {
         let cli = Cli {
             config: tmpfile.path().to_owned(),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
         let result = cli.get_config();
         let config = result.unwrap();
@@ -172,6 +380,9 @@ pub mod test {
         let cli = Cli {
             config: tmpfile.path().to_owned(),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
         let result = cli.get_config();
         let config = result.unwrap();
@@ -191,6 +402,183 @@ pub mod test {
         assert_eq!(config, expected_config);
     }
 
+    // This function tests that environment variables take precedence over the config file.
+    #[test]
+    fn test_cli_get_config_env_override() {
+        let json = r#"{
+            "http_request_timeout_secs": 20,
+            "http_connect_timeout_secs": 5
+        }"#;
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let vars = vec![
+            (
+                "IC_HTTP_ADAPTER_HTTP_REQUEST_TIMEOUT_SECS".to_string(),
+                "7".to_string(),
+            ),
+            (
+                "IC_HTTP_ADAPTER_IP_FAMILY".to_string(),
+                "V4Only".to_string(),
+            ),
+            (
+                "IC_HTTP_ADAPTER_MIN_TLS_VERSION".to_string(),
+                "1.2".to_string(),
+            ),
+            ("HTTP_REQUEST_TIMEOUT_SECS".to_string(), "9".to_string()),
+        ];
+        let config = cli.get_config_with_env(vars.into_iter()).unwrap();
+        let expected_config = Config {
+            http_request_timeout_secs: 7,
+            http_connect_timeout_secs: 5,
+            ip_family: IpFamily::V4Only,
+            min_tls_version: Some("1.2".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(config, expected_config);
+    }
+
+    // This function tests that a negative pool idle timeout is rejected.
+    #[test]
+    fn test_cli_get_config_negative_pool_idle_timeout() {
+        let json = r#"{
+            "pool_idle_timeout_secs": -1
+        }"#;
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let result = cli.get_config();
+        assert!(matches!(result, Err(CliError::Deserialize(_))));
+    }
+
+    // This function tests that injected headers with an invalid name are rejected.
+    #[test]
+    fn test_cli_get_config_invalid_injected_header() {
+        let json = r#"{
+            "injected_headers": [["Bad Name", "value"]]
+        }"#;
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let result = cli.get_config();
+        assert!(matches!(result, Err(CliError::InjectedHeaders(_))));
+    }
+
+    // This function tests that an unsupported minimum TLS version is rejected.
+    #[test]
+    fn test_cli_get_config_invalid_min_tls_version() {
+        let json = r#"{
+            "min_tls_version": "1.3"
+        }"#;
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let result = cli.get_config();
+        assert!(matches!(result, Err(CliError::MinTlsVersion(ref err)) if err.contains("TLS 1.3")));
+    }
+
+    // This function tests that a client certificate without a key is rejected.
+    #[test]
+    fn test_cli_get_config_client_cert_without_key() {
+        let json = r#"{
+            "client_cert_path": "/tmp/client.pem"
+        }"#;
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let result = cli.get_config();
+        assert!(matches!(result, Err(CliError::ClientIdentity(_))));
+    }
+
+    // This function tests that a client identity that does not parse is rejected.
+    #[test]
+    fn test_cli_get_config_unparseable_client_identity() {
+        let mut pem = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(pem, "not a PEM file").expect("Failed to write to tmp file");
+        let json = format!(
+            r#"{{
+            "client_cert_path": {0:?},
+            "client_key_path": {0:?}
+        }}"#,
+            pem.path()
+        );
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let result = cli.get_config();
+        assert!(matches!(result, Err(CliError::ClientIdentity(_))));
+    }
+
+    // This function tests that a local address that does not belong to the host is rejected.
+    #[test]
+    fn test_cli_get_config_unassignable_local_address() {
+        let json = r#"{
+            "local_address": "192.0.2.1"
+        }"#;
+
+        let mut tmpfile = NamedTempFile::new().expect("Failed to create tmp file");
+        writeln!(tmpfile, "{}", json).expect("Failed to write to tmp file");
+
+        let cli = Cli {
+            config: tmpfile.path().to_owned(),
+            verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
+        };
+        let result = cli.get_config();
+        assert!(matches!(result, Err(CliError::LocalAddress(_, _))));
+    }
+
     // This function tests a fully specified config file. It overwrites all default values.
     #[test]
     fn test_cli_get_full_config_json() {
@@ -199,9 +587,53 @@ pub mod test {
             "http_connect_timeout_secs": 20,
             "http_request_timeout_secs": 50,
             "http_request_size_limit_bytes": 1073741824,
+            "time_to_first_byte_timeout_secs": 10,
+            "read_idle_timeout_secs": 5,
+            "decompress_responses": true,
+            "pool_max_idle_per_host": 16,
+            "pool_idle_timeout_secs": 30,
+            "replay_window_secs": 120,
+            "require_https": true,
+            "allowed_methods": ["GET", "HEAD"],
+            "local_address": "127.0.0.1",
+            "ip_family": "V6Only",
+            "max_cache_bytes": 1048576,
+            "circuit_breaker_failure_threshold": 3,
+            "circuit_breaker_window_secs": 10,
+            "circuit_breaker_cooldown_secs": 15,
+            "canister_rate_limit_per_sec": 5,
+            "canister_rate_limit_burst": 20,
+            "max_request_bytes": 4096,
+            "injected_headers": [["X-Api-Token", "secret"]],
+            "user_agent": "my-adapter/1.0",
+            "tls_handshake_timeout_secs": 4,
+            "trace_http": true,
+            "max_concurrent_requests": 100,
+            "concurrency_queue_timeout_secs": 2,
+            "idempotency_cache_bytes": 65536,
+            "idempotency_ttl_secs": 30,
+            "http2_keepalive_interval_secs": 60,
+            "http2_keepalive_timeout_secs": 10,
+            "tcp_nodelay": false,
+            "tcp_send_buffer_bytes": 65536,
+            "tcp_recv_buffer_bytes": 131072,
+            "min_tls_version": "1.2",
+            "client_cert_path": null,
+            "client_key_path": null,
+            "host_connect_timeouts": {"slow.example.com": 30},
+            "max_retries": 2,
+            "retry_backoff_millis": 250,
+            "total_deadline_secs": 10,
+            "max_response_headers": 50,
+            "max_response_header_bytes": 8192,
+            "http2_prior_knowledge": true,
+            "sort_response_headers": true,
+            "dns_cache_ttl_secs": 60,
+            "max_url_length": 2048,
             "incoming_source": {
                     "Path": "/tmp/path.socket"
             },
+            "socket_mode": 384,
             "logger": {
                 "node_id": 0,
                 "dc_id": 200,
@@ -221,6 +653,9 @@ pub mod test {
         let cli = Cli {
             config: tmpfile.path().to_owned(),
             verbose: true,
+            check_config: false,
+            listen: None,
+            log_level: None,
         };
         let result = cli.get_config();
         let config = result.unwrap();
@@ -228,7 +663,53 @@ pub mod test {
             http_connect_timeout_secs: 20,
             http_request_timeout_secs: 50,
             http_request_size_limit_bytes: 1073741824,
+            time_to_first_byte_timeout_secs: 10,
+            read_idle_timeout_secs: 5,
+            decompress_responses: true,
+            pool_max_idle_per_host: 16,
+            pool_idle_timeout_secs: 30,
+            replay_window_secs: 120,
+            require_https: true,
+            allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+            local_address: Some("127.0.0.1".parse().unwrap()),
+            ip_family: IpFamily::V6Only,
+            max_cache_bytes: 1048576,
+            circuit_breaker_failure_threshold: 3,
+            circuit_breaker_window_secs: 10,
+            circuit_breaker_cooldown_secs: 15,
+            canister_rate_limit_per_sec: 5,
+            canister_rate_limit_burst: 20,
+            max_request_bytes: 4096,
+            injected_headers: vec![("X-Api-Token".to_string(), "secret".to_string())],
+            user_agent: Some("my-adapter/1.0".to_string()),
+            tls_handshake_timeout_secs: 4,
+            trace_http: true,
+            max_concurrent_requests: 100,
+            concurrency_queue_timeout_secs: 2,
+            idempotency_cache_bytes: 65536,
+            idempotency_ttl_secs: 30,
+            http2_keepalive_interval_secs: Some(60),
+            http2_keepalive_timeout_secs: Some(10),
+            tcp_nodelay: false,
+            tcp_send_buffer_bytes: Some(65536),
+            tcp_recv_buffer_bytes: Some(131072),
+            min_tls_version: Some("1.2".to_string()),
+            client_cert_path: None,
+            client_key_path: None,
+            host_connect_timeouts: vec![("slow.example.com".to_string(), 30)]
+                .into_iter()
+                .collect(),
+            max_retries: 2,
+            retry_backoff_millis: 250,
+            total_deadline_secs: Some(10),
+            max_response_headers: 50,
+            max_response_header_bytes: 8192,
+            http2_prior_knowledge: true,
+            sort_response_headers: true,
+            dns_cache_ttl_secs: 60,
+            max_url_length: 2048,
             incoming_source: IncomingSource::Path(PathBuf::from("/tmp/path.socket")),
+            socket_mode: Some(0o600),
             logger: ic_config::logger::Config {
                 node_id: 0,
                 dc_id: 200,
@@ -241,4 +722,63 @@ pub mod test {
 
         assert_eq!(config, expected_config);
     }
+
+    // This function tests that a config that fails to serialize is still formatted.
+    #[test]
+    fn test_config_to_string_falls_back_to_debug() {
+        #[derive(Debug)]
+        struct Unserializable;
+
+        impl Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("not serializable"))
+            }
+        }
+
+        let formatted = config_to_string(&Unserializable);
+        assert!(formatted.starts_with("Unserializable"));
+        assert!(formatted.contains("not serializable"));
+
+        assert!(config_to_string(&Config::default()).contains("\"require_https\": false"));
+    }
+
+    // This function tests that the values of the injected headers are not formatted.
+    #[test]
+    fn test_config_to_string_redacts_injected_headers() {
+        let config = Config {
+            injected_headers: vec![("x-api-key".to_string(), "secret-token".to_string())],
+            ..Default::default()
+        };
+        let formatted = config_to_string(&config);
+        assert!(formatted.contains("x-api-key"));
+        assert!(formatted.contains("<redacted>"));
+        assert!(!formatted.contains("secret-token"));
+    }
+
+    // This function tests that the --listen flag accepts TCP addresses and paths.
+    #[test]
+    fn test_cli_listen_address() {
+        let cli = Cli::try_parse_from(vec!["adapter", "config.json", "--listen", "127.0.0.1:8080"])
+            .expect("Failed to parse arguments");
+        assert_eq!(
+            cli.listen,
+            Some(ListenAddress::Tcp("127.0.0.1:8080".parse().unwrap()))
+        );
+
+        let cli = Cli::try_parse_from(vec![
+            "adapter",
+            "config.json",
+            "--listen",
+            "/tmp/adapter.sock",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(
+            cli.listen,
+            Some(ListenAddress::Path(PathBuf::from("/tmp/adapter.sock")))
+        );
+
+        let cli =
+            Cli::try_parse_from(vec!["adapter", "config.json"]).expect("Failed to parse arguments");
+        assert_eq!(cli.listen, None);
+    }
 }
@@ -0,0 +1,807 @@
+use crate::config::{Config, IpFamily};
+use hyper::{
+    client::{
+        connect::{
+            dns::{GaiResolver, Name},
+            Connected, Connection,
+        },
+        Builder, HttpConnector,
+    },
+    service::Service,
+    Body, Client, Uri,
+};
+use hyper_tls::MaybeHttpsStream;
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A resolver that only keeps the resolved addresses of the configured IP
+/// family. Resolution fails if no such address exists.
+#[derive(Clone, Debug)]
+pub struct FamilyResolver<R = CachingResolver> {
+    inner: R,
+    ip_family: IpFamily,
+}
+
+impl<R> FamilyResolver<R> {
+    pub fn new(inner: R, ip_family: IpFamily) -> Self {
+        Self { inner, ip_family }
+    }
+}
+
+impl<R> Service<Name> for FamilyResolver<R>
+where
+    R: Service<Name, Error = io::Error>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Future: Send + 'static,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let ip_family = self.ip_family;
+        let resolving = self.inner.call(name.clone());
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await?
+                .filter(|addr| ip_family.allows(&addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{} has no address allowed by {:?}", name, ip_family),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// A resolver that caches the resolved addresses of each name for a fixed
+/// TTL, so that hosts that are requested often are not resolved every time.
+/// A zero TTL disables the cache.
+#[derive(Clone, Debug)]
+pub struct CachingResolver<R = GaiResolver> {
+    inner: R,
+    ttl: Duration,
+    /// The resolved addresses by name, with the time they expire.
+    cache: Arc<Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>>,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<R> Service<Name> for CachingResolver<R>
+where
+    R: Service<Name, Error = io::Error>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Future: Send + 'static,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let key = name.as_str().to_string();
+        if let Some((expires_at, addrs)) = self.cache.lock().unwrap().get(&key) {
+            if Instant::now() < *expires_at {
+                let addrs = addrs.clone();
+                return Box::pin(async move { Ok(addrs.into_iter()) });
+            }
+        }
+        let ttl = self.ttl;
+        let cache = self.cache.clone();
+        let resolving = self.inner.call(name);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving.await?.collect();
+            if !ttl.is_zero() {
+                let now = Instant::now();
+                let mut cache = cache.lock().unwrap();
+                cache.retain(|_, (expires_at, _)| now < *expires_at);
+                cache.insert(key, (now + ttl, addrs.clone()));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// A connector that fails if the wrapped connector does not establish the
+/// connection within the given timeout.
+#[derive(Clone, Debug)]
+pub struct TimeoutConnector<C> {
+    inner: C,
+    timeout: Duration,
+    /// The timeouts that override `timeout` for some hosts.
+    host_timeouts: Arc<HashMap<String, Duration>>,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub fn new(inner: C, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            host_timeouts: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Applies the given timeouts to connections to the respective hosts
+    /// instead of the default one.
+    pub fn with_host_timeouts(self, host_timeouts: HashMap<String, Duration>) -> Self {
+        Self {
+            host_timeouts: Arc::new(host_timeouts),
+            ..self
+        }
+    }
+
+    /// Returns the timeout of connections to the host of `uri`.
+    fn timeout_for(&self, uri: &Uri) -> Duration {
+        uri.host()
+            .and_then(|host| self.host_timeouts.get(host))
+            .copied()
+            .unwrap_or(self.timeout)
+    }
+}
+
+impl<C> Service<Uri> for TimeoutConnector<C>
+where
+    C: Service<Uri>,
+    C::Error: Into<Box<dyn Error + Send + Sync>>,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let timeout = self.timeout_for(&uri);
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, connecting).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connection not established within {:?}", timeout),
+                )
+                .into()),
+            }
+        })
+    }
+}
+
+/// A connector that performs the TLS handshake over the connections
+/// established by the wrapped connector. Only `https` URIs are accepted, and
+/// the connection fails if the handshake does not complete within the given
+/// timeout.
+#[derive(Clone)]
+pub struct TlsHandshakeConnector<C> {
+    inner: C,
+    tls: tokio_native_tls::TlsConnector,
+    handshake_timeout: Duration,
+}
+
+impl<C> TlsHandshakeConnector<C> {
+    pub fn new(inner: C, tls: native_tls::TlsConnector, handshake_timeout: Duration) -> Self {
+        Self {
+            inner,
+            tls: tls.into(),
+            handshake_timeout,
+        }
+    }
+}
+
+impl<C> Service<Uri> for TlsHandshakeConnector<C>
+where
+    C: Service<Uri>,
+    C::Response: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C::Error: Into<Box<dyn Error + Send + Sync>>,
+    C::Future: Send + 'static,
+{
+    type Response = MaybeHttpsStream<C::Response>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if uri.scheme_str() != Some("https") {
+            return Box::pin(async {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "The URI is not HTTPS").into())
+            });
+        }
+        // IPv6 addresses are enclosed in brackets in URIs, but not in the
+        // names the certificates are verified against.
+        let host = uri
+            .host()
+            .unwrap_or("")
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let connecting = self.inner.call(uri);
+        let tls = self.tls.clone();
+        let handshake_timeout = self.handshake_timeout;
+        Box::pin(async move {
+            let tcp = connecting.await.map_err(Into::into)?;
+            match tokio::time::timeout(handshake_timeout, tls.connect(&host, tcp)).await {
+                Ok(result) => Ok(MaybeHttpsStream::Https(result?)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("TLS handshake not completed within {:?}", handshake_timeout),
+                )
+                .into()),
+            }
+        })
+    }
+}
+
+/// The DER-encoded leaf certificate presented by the server of a TLS
+/// connection. It is an extension of the responses received over the
+/// connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerCertificate(pub Vec<u8>);
+
+/// A connector that records the certificate of the server of every TLS
+/// connection established by the wrapped connector, such that it is available
+/// as a [PeerCertificate] extension of the responses.
+#[derive(Clone, Debug)]
+pub struct PeerCertificateConnector<C> {
+    inner: C,
+}
+
+impl<C> PeerCertificateConnector<C> {
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<C, T> Service<Uri> for PeerCertificateConnector<C>
+where
+    C: Service<Uri, Response = MaybeHttpsStream<T>>,
+    C::Future: Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Response = PeerCertificateStream<MaybeHttpsStream<T>>;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            let stream = connecting.await?;
+            let peer_certificate = match &stream {
+                MaybeHttpsStream::Https(tls) => tls
+                    .get_ref()
+                    .peer_certificate()
+                    .ok()
+                    .flatten()
+                    .and_then(|certificate| certificate.to_der().ok())
+                    .map(PeerCertificate),
+                MaybeHttpsStream::Http(_) => None,
+            };
+            Ok(PeerCertificateStream {
+                inner: stream,
+                peer_certificate,
+            })
+        })
+    }
+}
+
+/// A connection established by [PeerCertificateConnector].
+pub struct PeerCertificateStream<S> {
+    inner: S,
+    peer_certificate: Option<PeerCertificate>,
+}
+
+impl<S: Connection> Connection for PeerCertificateStream<S> {
+    fn connected(&self) -> Connected {
+        let connected = self.inner.connected();
+        match &self.peer_certificate {
+            Some(peer_certificate) => connected.extra(peer_certificate.clone()),
+            None => connected,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeerCertificateStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeerCertificateStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Builds the connector that opens the TCP connections for outgoing requests.
+pub fn build_http_connector(config: &Config) -> HttpConnector<FamilyResolver> {
+    let resolver = FamilyResolver::new(
+        CachingResolver::new(
+            GaiResolver::new(),
+            Duration::from_secs(config.dns_cache_ttl_secs),
+        ),
+        config.ip_family,
+    );
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    // The scheme is checked by the HTTPS connector wrapping this one.
+    http.enforce_http(false);
+    http.set_local_address(config.local_address);
+    // The connect timeouts of hosts with an override are enforced by the
+    // `TimeoutConnector` wrapping this one, so they must not be cut short here.
+    let connect_timeout_secs = config
+        .host_connect_timeouts
+        .values()
+        .copied()
+        .fold(config.http_connect_timeout_secs, u64::max);
+    http.set_connect_timeout(Some(Duration::from_secs(connect_timeout_secs)));
+    http.set_nodelay(config.tcp_nodelay);
+    http.set_send_buffer_size(config.tcp_send_buffer_bytes);
+    http.set_recv_buffer_size(config.tcp_recv_buffer_bytes);
+    http
+}
+
+/// Builds the HTTPS-only client used to make outgoing requests, with the
+/// connection pool tuned according to `config`.
+pub fn build_https_client(
+    config: &Config,
+) -> Client<
+    PeerCertificateConnector<
+        TlsHandshakeConnector<TimeoutConnector<HttpConnector<FamilyResolver>>>,
+    >,
+    Body,
+> {
+    build_https_client_with_roots(config, &[])
+}
+
+/// Same as `build_https_client()`, but also trusts the given root
+/// certificates, which allows tests to connect to servers with certificates
+/// of their own CA.
+fn build_https_client_with_roots(
+    config: &Config,
+    root_certificates: &[native_tls::Certificate],
+) -> Client<
+    PeerCertificateConnector<
+        TlsHandshakeConnector<TimeoutConnector<HttpConnector<FamilyResolver>>>,
+    >,
+    Body,
+> {
+    let mut tls = native_tls::TlsConnector::builder();
+    for certificate in root_certificates {
+        tls.add_root_certificate(certificate.clone());
+    }
+    tls.min_protocol_version(
+        config
+            .min_tls_protocol()
+            .expect("The minimum TLS version is validated when the config is loaded"),
+    );
+    if let Some(identity) = config
+        .client_identity()
+        .expect("The client identity is validated when the config is loaded")
+    {
+        tls.identity(identity);
+    }
+    let tls = tls.build().expect("Failed to build the TLS connector");
+    let tcp = TimeoutConnector::new(
+        build_http_connector(config),
+        Duration::from_secs(config.http_connect_timeout_secs),
+    )
+    .with_host_timeouts(
+        config
+            .host_connect_timeouts
+            .iter()
+            .map(|(host, connect_timeout_secs)| {
+                (host.clone(), Duration::from_secs(*connect_timeout_secs))
+            })
+            .collect(),
+    );
+    let https = PeerCertificateConnector::new(TlsHandshakeConnector::new(
+        tcp,
+        tls,
+        Duration::from_secs(config.tls_handshake_timeout_secs),
+    ));
+    build_client_builder(config).build::<_, Body>(https)
+}
+
+/// Returns the builder of the clients used to make outgoing requests, with the
+/// connection pool and the HTTP version configured according to `config`.
+pub fn build_client_builder(config: &Config) -> Builder {
+    let mut builder = Client::builder();
+    builder
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+        .http2_only(config.http2_prior_knowledge);
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        ssl::{SslAcceptor, SslMethod, SslVerifyMode, SslVersion},
+        x509::{
+            extension::{BasicConstraints, SubjectAlternativeName},
+            X509NameBuilder, X509,
+        },
+    };
+    use std::{
+        io::{Read, Write},
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tempfile::NamedTempFile;
+    use tower::service_fn;
+
+    /// Generates a key and a certificate for `localhost`, signed by `issuer`,
+    /// or a self-signed CA certificate if there is no issuer. The CA is named
+    /// differently, otherwise OpenSSL takes its leaves for self-signed.
+    fn generate_cert(issuer: Option<&(PKey<Private>, X509)>) -> (PKey<Private>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        let common_name = if issuer.is_some() {
+            "localhost"
+        } else {
+            "Test CA"
+        };
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_key, issuer_cert)) => {
+                cert.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .dns("localhost")
+                    .build(&cert.x509v3_context(Some(issuer_cert), None))
+                    .unwrap();
+                cert.append_extension(san).unwrap();
+                cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                cert.set_issuer_name(&name).unwrap();
+                cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                cert.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (key, cert.build())
+    }
+
+    /// Spawns an HTTPS server on localhost with a certificate signed by `ca`
+    /// that only speaks the given TLS version and, if `verify_client` is set,
+    /// requires a client certificate signed by `ca`. Returns the URL of the
+    /// server.
+    fn spawn_tls_server(
+        ca: &(PKey<Private>, X509),
+        version: SslVersion,
+        verify_client: bool,
+    ) -> Uri {
+        let (key, cert) = generate_cert(Some(ca));
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_min_proto_version(Some(version)).unwrap();
+        acceptor.set_max_proto_version(Some(version)).unwrap();
+        // Recent OpenSSL versions refuse TLS 1.0 at the default security level.
+        acceptor.set_cipher_list("DEFAULT:@SECLEVEL=0").unwrap();
+        if verify_client {
+            acceptor.cert_store_mut().add_cert(ca.1.clone()).unwrap();
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(mut stream) = acceptor.accept(stream.unwrap()) {
+                    let mut request = [0u8; 4096];
+                    let _ = stream.read(&mut request);
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    );
+                }
+            }
+        });
+        format!("https://localhost:{}", port).parse().unwrap()
+    }
+
+    /// Returns the certificate of `ca` for the TLS connector of the client.
+    fn root_certificate(ca: &(PKey<Private>, X509)) -> native_tls::Certificate {
+        native_tls::Certificate::from_der(&ca.1.to_der().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_build_https_client_with_custom_pool() {
+        let config = Config {
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout_secs: 5,
+            ..Default::default()
+        };
+        let client = build_https_client(&config);
+
+        // The client still enforces HTTPS.
+        let result = client
+            .get("http://127.0.0.1:1".parse().expect("Bad uri"))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_http_connector_with_socket_options() {
+        let config = Config {
+            tcp_nodelay: true,
+            tcp_send_buffer_bytes: Some(64 * 1024),
+            tcp_recv_buffer_bytes: Some(64 * 1024),
+            ..Default::default()
+        };
+        let mut connector = build_http_connector(&config);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .expect("Bad uri");
+        let stream = connector.call(uri).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_min_tls_version_refuses_older_server() {
+        let ca = generate_cert(None);
+        let config = Config {
+            min_tls_version: Some("1.2".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.min_tls_protocol(),
+            Ok(Some(native_tls::Protocol::Tlsv12))
+        ));
+        let client = build_https_client_with_roots(&config, &[root_certificate(&ca)]);
+
+        let tls_1_0_only = spawn_tls_server(&ca, SslVersion::TLS1, false);
+        assert!(client.get(tls_1_0_only).await.is_err());
+
+        // The server is trusted, so only its TLS version is refused.
+        let tls_1_2 = spawn_tls_server(&ca, SslVersion::TLS1_2, false);
+        let response = client.get(tls_1_2).await.unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_client_identity_for_mutual_tls() {
+        let ca = generate_cert(None);
+        let url = spawn_tls_server(&ca, SslVersion::TLS1_2, true);
+
+        // The server refuses clients without a certificate.
+        let client = build_https_client_with_roots(&Config::default(), &[root_certificate(&ca)]);
+        assert!(client.get(url.clone()).await.is_err());
+
+        let (key, cert) = generate_cert(Some(&ca));
+        let mut cert_file = NamedTempFile::new().unwrap();
+        cert_file.write_all(&cert.to_pem().unwrap()).unwrap();
+        let mut key_file = NamedTempFile::new().unwrap();
+        key_file
+            .write_all(&key.private_key_to_pem_pkcs8().unwrap())
+            .unwrap();
+        let config = Config {
+            client_cert_path: Some(cert_file.path().to_owned()),
+            client_key_path: Some(key_file.path().to_owned()),
+            ..Default::default()
+        };
+        let client = build_https_client_with_roots(&config, &[root_certificate(&ca)]);
+        let response = client.get(url).await.unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_to_unreachable_host() {
+        let config = Config {
+            http_connect_timeout_secs: 1,
+            ..Default::default()
+        };
+        let mut connector = build_http_connector(&config);
+
+        // The address is not routable, so the connection attempt never completes.
+        let start = Instant::now();
+        let result = connector
+            .call("http://10.255.255.1:81".parse().expect("Bad uri"))
+            .await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_host_connect_timeout_override() {
+        // A connector that never establishes a connection.
+        let never = service_fn(|_: Uri| futures::future::pending::<io::Result<()>>());
+        let mut connector = TimeoutConnector::new(never, Duration::from_secs(1))
+            .with_host_timeouts(
+                vec![("slow.example.com".to_string(), Duration::from_secs(3))]
+                    .into_iter()
+                    .collect(),
+            );
+
+        let start = Instant::now();
+        let result = connector
+            .call("https://fast.example.com".parse().expect("Bad uri"))
+            .await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let start = Instant::now();
+        let result = connector
+            .call("https://slow.example.com".parse().expect("Bad uri"))
+            .await;
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() {
+        let config = Config {
+            http_connect_timeout_secs: 10,
+            tls_handshake_timeout_secs: 1,
+            ..Default::default()
+        };
+        let client = build_https_client(&config);
+
+        // The server accepts connections but never answers the handshake.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let start = Instant::now();
+        let uri = format!("https://{}", addr).parse().expect("Bad uri");
+        let result = client.get(uri).await;
+        assert!(result.is_err());
+        // The handshake is not granted the unused part of the connect timeout.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(3));
+    }
+
+    /// Resolves the name of a dual-stack host with the given IP family.
+    async fn resolve_dual_stack(ip_family: IpFamily) -> io::Result<Vec<SocketAddr>> {
+        let dual_stack = service_fn(|_: Name| async {
+            Ok::<_, io::Error>(
+                vec![
+                    SocketAddr::from(([192, 0, 2, 1], 0)),
+                    SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 0)),
+                ]
+                .into_iter(),
+            )
+        });
+        let mut resolver = FamilyResolver::new(dual_stack, ip_family);
+        let name = Name::from_str("dual-stack.example.com").expect("Bad name");
+        resolver.call(name).await.map(Iterator::collect)
+    }
+
+    #[tokio::test]
+    async fn test_family_resolver_any() {
+        let addrs = resolve_dual_stack(IpFamily::Any).await.unwrap();
+        assert_eq!(addrs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_family_resolver_v4_only() {
+        let addrs = resolve_dual_stack(IpFamily::V4Only).await.unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from(([192, 0, 2, 1], 0))]);
+    }
+
+    #[tokio::test]
+    async fn test_family_resolver_v6_only() {
+        let addrs = resolve_dual_stack(IpFamily::V6Only).await.unwrap();
+        assert_eq!(
+            addrs,
+            vec![SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 0))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_caching_resolver_resolves_once_within_ttl() {
+        let resolutions = Arc::new(AtomicUsize::new(0));
+        let counting = {
+            let resolutions = resolutions.clone();
+            service_fn(move |_: Name| {
+                resolutions.fetch_add(1, Ordering::SeqCst);
+                async {
+                    Ok::<_, io::Error>(vec![SocketAddr::from(([192, 0, 2, 1], 0))].into_iter())
+                }
+            })
+        };
+        let name = Name::from_str("cached.example.com").expect("Bad name");
+
+        let mut resolver = CachingResolver::new(counting.clone(), Duration::from_millis(500));
+        for _ in 0..3 {
+            let addrs: Vec<_> = resolver.call(name.clone()).await.unwrap().collect();
+            assert_eq!(addrs, vec![SocketAddr::from(([192, 0, 2, 1], 0))]);
+        }
+        assert_eq!(resolutions.load(Ordering::SeqCst), 1);
+
+        // The name is resolved again once the TTL passed.
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        resolver.call(name.clone()).await.unwrap();
+        assert_eq!(resolutions.load(Ordering::SeqCst), 2);
+
+        // A zero TTL disables the cache.
+        let mut resolver = CachingResolver::new(counting, Duration::ZERO);
+        resolver.call(name.clone()).await.unwrap();
+        resolver.call(name).await.unwrap();
+        assert_eq!(resolutions.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_family_resolver_without_matching_address() {
+        let v4_only = service_fn(|_: Name| async {
+            Ok::<_, io::Error>(vec![SocketAddr::from(([192, 0, 2, 1], 0))].into_iter())
+        });
+        let mut resolver = FamilyResolver::new(v4_only, IpFamily::V6Only);
+        let name = Name::from_str("v4-only.example.com").expect("Bad name");
+        let err = resolver.call(name).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
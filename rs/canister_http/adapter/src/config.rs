@@ -1,10 +1,33 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use ic_config::logger::Config as LoggerConfig;
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use native_tls::{Identity, Protocol};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{collections::HashMap, fs, net::IpAddr, path::PathBuf};
 
 const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 1;
 const DEFAULT_HTTP_REQUEST_TIMEOUT_SECS: u64 = 3;
 const DEFAULT_HTTP_REQUEST_SIZE_LIMIT_BYTES: u64 = 1048576; // 1Mb
+const DEFAULT_TIME_TO_FIRST_BYTE_TIMEOUT_SECS: u64 = 3;
+const DEFAULT_READ_IDLE_TIMEOUT_SECS: u64 = 3;
+// The pool defaults match the ones of hyper's client builder.
+const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = usize::MAX;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+const DEFAULT_REPLAY_WINDOW_SECS: u64 = 300;
+const DEFAULT_CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_BREAKER_WINDOW_SECS: u64 = 60;
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECS: u64 = 30;
+const DEFAULT_CANISTER_RATE_LIMIT_BURST: u64 = 10;
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 2 * 1024 * 1024; // 2Mb
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_IDEMPOTENCY_CACHE_BYTES: usize = 16 * 1024 * 1024; // 16Mb
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 60;
+const DEFAULT_RETRY_BACKOFF_MILLIS: u64 = 100;
+const DEFAULT_DNS_CACHE_TTL_SECS: u64 = 30;
+const DEFAULT_MAX_URL_LENGTH: usize = 8192;
+// hyper does not parse more than 100 response headers anyway.
+const DEFAULT_MAX_RESPONSE_HEADERS: usize = 100;
+const DEFAULT_MAX_RESPONSE_HEADER_BYTES: usize = 64 * 1024; // 64Kb
+const DEFAULT_USER_AGENT: &str = concat!("ic-canister-http-adapter/", env!("CARGO_PKG_VERSION"));
 
 #[derive(Clone, Debug, Deserialize, Eq, Serialize, PartialEq)]
 /// The source of the unix domain socket to be used for inter-process
@@ -22,24 +45,309 @@ impl Default for IncomingSource {
     }
 }
 
+/// The IP family that outgoing connections may use.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Serialize, PartialEq)]
+pub enum IpFamily {
+    /// Both IPv4 and IPv6 addresses are used.
+    Any,
+    /// Only IPv4 addresses are used.
+    V4Only,
+    /// Only IPv6 addresses are used.
+    V6Only,
+}
+
+impl IpFamily {
+    /// Returns true if connections to `ip` are allowed.
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::V4Only => ip.is_ipv4(),
+            IpFamily::V6Only => ip.is_ipv6(),
+        }
+    }
+}
+
+impl Default for IpFamily {
+    fn default() -> Self {
+        IpFamily::Any
+    }
+}
+
 /// This struct contains configuration options for the HTTP Adapter.
 #[derive(Clone, Debug, Deserialize, Eq, Serialize, PartialEq)]
 #[serde(default)]
 pub struct Config {
+    /// The maximum time to establish the TCP connection to a host, including
+    /// the DNS resolution.
     pub http_connect_timeout_secs: u64,
     pub http_request_timeout_secs: u64,
+    /// The maximum size of a response body, both as received and after
+    /// decompression. Larger responses are rejected.
     pub http_request_size_limit_bytes: u64,
+    /// The maximum time to wait for the response headers once the request has
+    /// been sent.
+    pub time_to_first_byte_timeout_secs: u64,
+    /// The maximum time to wait for the next chunk of the response body. This
+    /// detects servers that stall in the middle of the body.
+    pub read_idle_timeout_secs: u64,
+    /// If set, gzip, deflate and brotli encoded response bodies are
+    /// decompressed before they are returned. Can be overridden per request.
+    pub decompress_responses: bool,
+    /// The maximum number of idle connections kept per host. Defaults to no
+    /// limit.
+    pub pool_max_idle_per_host: usize,
+    /// The time after which an idle pooled connection is closed. Defaults to
+    /// 90 seconds.
+    pub pool_idle_timeout_secs: u64,
+    /// The time window during which a request nonce is remembered. A request
+    /// reusing a nonce within this window is rejected as a replay.
+    pub replay_window_secs: u64,
+    /// If set, requests whose URL scheme is not `https` are rejected before
+    /// they are dispatched.
+    pub require_https: bool,
+    /// The HTTP methods requests may use, compared case-insensitively. If
+    /// empty, all methods are allowed.
+    pub allowed_methods: Vec<String>,
+    /// The local address outgoing connections are made from. If not set, the
+    /// operating system picks one.
+    pub local_address: Option<IpAddr>,
+    /// Restricts outgoing connections to addresses of the given IP family.
+    pub ip_family: IpFamily,
+    /// The maximum total size of the response bodies cached by the adapter.
+    /// The least recently used responses are evicted to stay within it. Only
+    /// the responses to requests that carry the canister ID in their metadata
+    /// are cached. Zero, the default, disables the cache.
+    pub max_cache_bytes: usize,
+    /// The number of consecutive failed requests to a host within
+    /// `circuit_breaker_window_secs` after which requests to the host are
+    /// rejected for `circuit_breaker_cooldown_secs`. Zero disables the
+    /// circuit breaker.
+    pub circuit_breaker_failure_threshold: u32,
+    pub circuit_breaker_window_secs: u64,
+    pub circuit_breaker_cooldown_secs: u64,
+    /// The number of requests per second each canister may make, if the
+    /// replica propagates the canister ID in the request metadata. Zero
+    /// disables the rate limit.
+    pub canister_rate_limit_per_sec: u64,
+    /// The number of requests a canister may make in a burst.
+    pub canister_rate_limit_burst: u64,
+    /// The maximum size of the body of an outgoing request. Larger requests
+    /// are rejected before they are dispatched.
+    pub max_request_bytes: u64,
+    /// Headers added to every outgoing request, e.g. an API gateway token.
+    /// Headers set by the canister take precedence. Their values are redacted
+    /// when the config is serialized.
+    #[serde(serialize_with = "serialize_redacted_headers")]
+    pub injected_headers: Vec<(String, String)>,
+    /// The User-Agent sent with outgoing requests that do not set one. Defaults
+    /// to `ic-canister-http-adapter/<version>`.
+    pub user_agent: Option<String>,
+    /// The maximum time for the TLS handshake once the TCP connection has been
+    /// established.
+    pub tls_handshake_timeout_secs: u64,
+    /// If set, the headers of outgoing requests and a preview of the response
+    /// bodies are logged at trace level. Credentials are redacted.
+    pub trace_http: bool,
+    /// The maximum number of requests that are processed concurrently. Zero
+    /// means unlimited.
+    pub max_concurrent_requests: usize,
+    /// How long a request waits for a slot once `max_concurrent_requests` are
+    /// in flight. Zero rejects such requests right away.
+    pub concurrency_queue_timeout_secs: u64,
+    /// The maximum total size of the responses kept to answer requests that
+    /// repeat an idempotency key.
+    pub idempotency_cache_bytes: usize,
+    /// How long the response to a request with an idempotency key is returned
+    /// to requests that repeat the key.
+    pub idempotency_ttl_secs: u64,
+    /// If set, the gRPC server pings the replica at this interval to keep idle
+    /// connections alive, e.g. behind a NAT.
+    pub http2_keepalive_interval_secs: Option<u64>,
+    /// If set, connections are closed if a keep-alive ping is not acknowledged
+    /// within this time.
+    pub http2_keepalive_timeout_secs: Option<u64>,
+    /// If set, Nagle's algorithm is disabled on outgoing connections, which
+    /// lowers the latency of the typically small requests.
+    pub tcp_nodelay: bool,
+    /// The size of the send buffer of outgoing connections. If not set, the
+    /// operating system default is used.
+    pub tcp_send_buffer_bytes: Option<usize>,
+    /// The size of the receive buffer of outgoing connections. If not set, the
+    /// operating system default is used.
+    pub tcp_recv_buffer_bytes: Option<usize>,
+    /// The minimum TLS version of outgoing connections: "1.0", "1.1" or "1.2".
+    /// If not set, the default of the TLS backend applies. "1.3" is rejected:
+    /// the native-tls version in use cannot set it as the minimum, nor report
+    /// the negotiated version for the adapter to check it.
+    pub min_tls_version: Option<String>,
+    /// The PEM certificate chain presented to upstreams requiring mutual TLS.
+    /// Must be set together with `client_key_path`.
+    pub client_cert_path: Option<PathBuf>,
+    /// The PEM PKCS #8 private key of `client_cert_path`.
+    pub client_key_path: Option<PathBuf>,
+    /// Overrides `http_connect_timeout_secs` for the hosts known to be slow, keyed
+    /// by the exact host name of the URL.
+    pub host_connect_timeouts: HashMap<String, u64>,
+    /// The number of times GET and HEAD requests are retried after a 5xx
+    /// response or a connection error. Zero disables retries.
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for every further retry.
+    pub retry_backoff_millis: u64,
+    /// Bounds the time of all attempts of a request, including the backoffs
+    /// between retries. If not set, only the timeouts of the single attempts
+    /// apply.
+    pub total_deadline_secs: Option<u64>,
+    /// The maximum number of headers of a response.
+    pub max_response_headers: usize,
+    /// The maximum total size of the names and values of the headers of a
+    /// response.
+    pub max_response_header_bytes: usize,
+    /// If set, outgoing requests use HTTP/2 with prior knowledge, i.e.,
+    /// without negotiating the version first. This is needed for upstreams
+    /// that only speak HTTP/2 over cleartext (h2c). Over TLS, the version is
+    /// not negotiated with ALPN either. Requests to servers that only support
+    /// HTTP/1 fail.
+    pub http2_prior_knowledge: bool,
+    /// If set, the headers of responses are sorted by name, so that the same
+    /// response is returned byte for byte, regardless of the order in which
+    /// the upstream sent the headers.
+    pub sort_response_headers: bool,
+    /// How long the resolved addresses of a host are reused. Zero disables the
+    /// cache, resolving the host for every connection.
+    pub dns_cache_ttl_secs: u64,
+    /// The maximum length of the URL of an outgoing request in bytes. Longer
+    /// URLs are rejected before they are dispatched.
+    pub max_url_length: usize,
     pub incoming_source: IncomingSource,
+    /// The permissions of the socket file if the adapter creates the unix
+    /// domain socket itself, i.e., not with systemd, as a decimal number. For
+    /// example, 384 is the mode 0o600. If not set, the umask applies.
+    pub socket_mode: Option<u32>,
     pub logger: LoggerConfig,
 }
 
+/// Serializes the headers with their values replaced by `<redacted>`, so that
+/// secrets do not end up in logs.
+fn serialize_redacted_headers<S: Serializer>(
+    headers: &[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(headers.iter().map(|(name, _)| (name, "<redacted>")))
+}
+
+impl Config {
+    /// Parses the injected headers. Fails if a name or value is not valid.
+    pub fn injected_header_map(&self) -> Result<HeaderMap, String> {
+        let mut map = HeaderMap::new();
+        for (name, value) in &self.injected_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|err| format!("invalid header name {:?}: {}", name, err))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|err| format!("invalid value of header {}: {}", name, err))?;
+            map.append(name, value);
+        }
+        Ok(map)
+    }
+
+    /// Returns the minimum TLS protocol version of outgoing connections. Fails
+    /// if the configured version is not supported.
+    pub fn min_tls_protocol(&self) -> Result<Option<Protocol>, String> {
+        match self.min_tls_version.as_deref() {
+            None => Ok(None),
+            Some("1.0") => Ok(Some(Protocol::Tlsv10)),
+            Some("1.1") => Ok(Some(Protocol::Tlsv11)),
+            Some("1.2") => Ok(Some(Protocol::Tlsv12)),
+            Some("1.3") => Err("TLS 1.3 cannot be required as the minimum version, \
+                 the highest supported minimum is \"1.2\""
+                .to_string()),
+            Some(version) => Err(format!(
+                "unsupported TLS version {:?}, expected \"1.0\", \"1.1\" or \"1.2\"",
+                version
+            )),
+        }
+    }
+
+    /// Loads the identity presented to upstreams requiring mutual TLS. Fails if
+    /// only one of the certificate and the key is configured or if they cannot
+    /// be read or parsed.
+    pub fn client_identity(&self) -> Result<Option<Identity>, String> {
+        match (&self.client_cert_path, &self.client_key_path) {
+            (None, None) => Ok(None),
+            (Some(cert_path), Some(key_path)) => {
+                let read = |path: &PathBuf| {
+                    fs::read(path)
+                        .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+                };
+                let cert = read(cert_path)?;
+                let key = read(key_path)?;
+                Identity::from_pkcs8(&cert, &key)
+                    .map(Some)
+                    .map_err(|err| format!("failed to parse the PEM identity: {}", err))
+            }
+            _ => Err("client_cert_path and client_key_path must be set together".to_string()),
+        }
+    }
+
+    /// Returns the User-Agent of outgoing requests. Fails if the configured
+    /// value is not a valid header value.
+    pub fn user_agent_header(&self) -> Result<HeaderValue, String> {
+        let user_agent = self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        HeaderValue::from_str(user_agent)
+            .map_err(|err| format!("invalid user agent {:?}: {}", user_agent, err))
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
             http_connect_timeout_secs: DEFAULT_HTTP_CONNECT_TIMEOUT_SECS,
             http_request_timeout_secs: DEFAULT_HTTP_REQUEST_TIMEOUT_SECS,
             http_request_size_limit_bytes: DEFAULT_HTTP_REQUEST_SIZE_LIMIT_BYTES,
+            time_to_first_byte_timeout_secs: DEFAULT_TIME_TO_FIRST_BYTE_TIMEOUT_SECS,
+            read_idle_timeout_secs: DEFAULT_READ_IDLE_TIMEOUT_SECS,
+            decompress_responses: false,
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout_secs: DEFAULT_POOL_IDLE_TIMEOUT_SECS,
+            replay_window_secs: DEFAULT_REPLAY_WINDOW_SECS,
+            require_https: false,
+            allowed_methods: Vec::new(),
+            local_address: None,
+            ip_family: IpFamily::default(),
+            max_cache_bytes: 0,
+            circuit_breaker_failure_threshold: DEFAULT_CIRCUIT_BREAKER_FAILURE_THRESHOLD,
+            circuit_breaker_window_secs: DEFAULT_CIRCUIT_BREAKER_WINDOW_SECS,
+            circuit_breaker_cooldown_secs: DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECS,
+            canister_rate_limit_per_sec: 0,
+            canister_rate_limit_burst: DEFAULT_CANISTER_RATE_LIMIT_BURST,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            injected_headers: Vec::new(),
+            user_agent: None,
+            tls_handshake_timeout_secs: DEFAULT_TLS_HANDSHAKE_TIMEOUT_SECS,
+            trace_http: false,
+            max_concurrent_requests: 0,
+            concurrency_queue_timeout_secs: 0,
+            idempotency_cache_bytes: DEFAULT_IDEMPOTENCY_CACHE_BYTES,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+            http2_keepalive_interval_secs: None,
+            http2_keepalive_timeout_secs: None,
+            tcp_nodelay: true,
+            tcp_send_buffer_bytes: None,
+            tcp_recv_buffer_bytes: None,
+            min_tls_version: None,
+            client_cert_path: None,
+            client_key_path: None,
+            host_connect_timeouts: HashMap::new(),
+            max_retries: 0,
+            retry_backoff_millis: DEFAULT_RETRY_BACKOFF_MILLIS,
+            total_deadline_secs: None,
+            max_response_headers: DEFAULT_MAX_RESPONSE_HEADERS,
+            max_response_header_bytes: DEFAULT_MAX_RESPONSE_HEADER_BYTES,
+            http2_prior_knowledge: false,
+            sort_response_headers: false,
+            dns_cache_ttl_secs: DEFAULT_DNS_CACHE_TTL_SECS,
+            max_url_length: DEFAULT_MAX_URL_LENGTH,
             incoming_source: IncomingSource::default(),
+            socket_mode: None,
             logger: LoggerConfig::default(),
         }
     }
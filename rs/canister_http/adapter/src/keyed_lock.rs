@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// A lock per key, so that concurrent holders of the same key take turns
+/// while holders of different keys proceed independently. A key is only
+/// tracked while it is held or waited for.
+pub struct KeyedLock<K> {
+    locks: Mutex<HashMap<K, KeyLock>>,
+}
+
+struct KeyLock {
+    lock: Arc<AsyncMutex<()>>,
+    /// The number of holders and waiters of the key.
+    users: usize,
+}
+
+/// Holds the lock of a key until dropped.
+pub struct KeyedLockGuard<'a, K: Eq + Hash> {
+    keyed_lock: &'a KeyedLock<K>,
+    key: K,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<K: Clone + Eq + Hash> KeyedLock<K> {
+    pub fn new() -> Self {
+        Self {
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until no one else holds `key`, then holds it until the returned
+    /// guard is dropped.
+    pub async fn lock(&self, key: K) -> KeyedLockGuard<'_, K> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            let entry = locks.entry(key.clone()).or_insert_with(|| KeyLock {
+                lock: Arc::new(AsyncMutex::new(())),
+                users: 0,
+            });
+            entry.users += 1;
+            entry.lock.clone()
+        };
+        // The guard is created before waiting, so that the key is released
+        // even if the wait is cancelled.
+        let mut guard = KeyedLockGuard {
+            keyed_lock: self,
+            key,
+            guard: None,
+        };
+        guard.guard = Some(lock.lock_owned().await);
+        guard
+    }
+
+    #[cfg(test)]
+    fn tracked_keys(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+impl<K: Eq + Hash> Drop for KeyedLockGuard<'_, K> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.keyed_lock.locks.lock().unwrap();
+        if let Some(entry) = locks.get_mut(&self.key) {
+            entry.users -= 1;
+            if entry.users == 0 {
+                locks.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_same_key_is_held_once() {
+        let keyed_lock = KeyedLock::new();
+        let guard = keyed_lock.lock("a").await;
+
+        // Another key is not blocked.
+        let other = keyed_lock.lock("b").await;
+        drop(other);
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), keyed_lock.lock("a"));
+        assert!(waiting.await.is_err());
+
+        drop(guard);
+        let guard = keyed_lock.lock("a").await;
+        drop(guard);
+        assert_eq!(keyed_lock.tracked_keys(), 0);
+    }
+}
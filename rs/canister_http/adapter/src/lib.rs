@@ -2,12 +2,37 @@
 //! This is part of the http calls from canister feature
 
 mod cli;
+/// This module builds the HTTP client used for outgoing requests.
+mod client;
 /// Main module of HTTP adapter. Receives gRPC calls from replica and makes outgoing requests
 mod rpc_server;
 
+/// This module tracks failing hosts to reject requests to them early.
+mod circuit_breaker;
+/// This module serializes concurrent holders of the same key.
+mod keyed_lock;
+/// This module contains a cache bounded by the total size of its values.
+mod lru_cache;
+/// This module tracks request nonces to detect replayed requests.
+mod nonce_cache;
+/// This module limits the rate of requests per canister.
+mod rate_limiter;
+/// This module contains the transforms applied to responses.
+mod transform;
+
 /// This module contains the basic configuration struct used to start up an adapter instance.
 mod config;
 
-pub use cli::Cli;
-pub use config::{Config, IncomingSource};
-pub use rpc_server::CanisterHttp;
+pub use cli::{config_to_string, Cli, ListenAddress};
+pub use client::{
+    build_client_builder, build_http_connector, build_https_client, CachingResolver,
+    FamilyResolver, PeerCertificate, PeerCertificateConnector, TimeoutConnector,
+    TlsHandshakeConnector,
+};
+pub use config::{Config, IncomingSource, IpFamily};
+pub use lru_cache::LruCache;
+pub use rpc_server::{
+    build_grpc_server, health_service_for, CanisterHttp, CANISTER_ID_METADATA_KEY,
+    IDEMPOTENCY_KEY_METADATA_KEY, PEER_CERTIFICATE_METADATA_KEY, REQUEST_ID_METADATA_KEY,
+};
+pub use transform::{IdentityTransform, ResponseTransform};
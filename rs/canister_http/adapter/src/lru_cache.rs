@@ -0,0 +1,134 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A cache that bounds the total size of its values in bytes. When the bound is
+/// exceeded, the least recently used entries are evicted.
+pub struct LruCache<K, V> {
+    max_bytes: usize,
+    total_bytes: usize,
+    /// The entries with their size and the time of their last use.
+    entries: HashMap<K, (V, usize, u64)>,
+    /// The keys ordered by the time of their last use.
+    recency: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            total_bytes: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Returns the value cached for `key` and marks it as most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let clock = self.tick();
+        let (value, _, last_used) = self.entries.get_mut(key)?;
+        let key = self
+            .recency
+            .remove(last_used)
+            .expect("Every entry has a recency");
+        self.recency.insert(clock, key);
+        *last_used = clock;
+        Some(value)
+    }
+
+    /// Caches `value`, which takes `size` bytes, for `key`, evicting the least
+    /// recently used entries as needed to stay within the bound. Values larger
+    /// than the bound are not cached.
+    pub fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.max_bytes {
+            return;
+        }
+        while self.total_bytes + size > self.max_bytes {
+            let oldest = *self.recency.keys().next().expect("The cache is not empty");
+            let lru_key = self.recency.remove(&oldest).expect("Key exists");
+            let (_, lru_size, _) = self.entries.remove(&lru_key).expect("Entry exists");
+            self.total_bytes -= lru_size;
+        }
+        let clock = self.tick();
+        self.recency.insert(clock, key.clone());
+        self.entries.insert(key, (value, size, clock));
+        self.total_bytes += size;
+    }
+
+    /// Removes the value cached for `key`, if any.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, size, last_used) = self.entries.remove(key)?;
+        self.recency.remove(&last_used);
+        self.total_bytes -= size;
+        Some(value)
+    }
+
+    /// Returns the total size of the cached values in bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used_entries() {
+        let mut cache = LruCache::new(100);
+        for key in 0..4 {
+            cache.insert(key, vec![0u8; 30], 30);
+        }
+        // The oldest entry was evicted to make room for the fourth one.
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&0).is_none());
+        assert_eq!(cache.total_bytes(), 90);
+
+        // Using an entry protects it from eviction.
+        assert!(cache.get(&1).is_some());
+        cache.insert(4, vec![0u8; 50], 50);
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&2).is_none());
+        assert!(cache.get(&3).is_none());
+        assert_eq!(cache.total_bytes(), 80);
+        assert!(cache.total_bytes() <= 100);
+    }
+
+    #[test]
+    fn test_does_not_cache_values_larger_than_the_bound() {
+        let mut cache = LruCache::new(100);
+        cache.insert(0, vec![0u8; 10], 10);
+        cache.insert(1, vec![0u8; 101], 101);
+        assert!(cache.get(&1).is_none());
+        assert_eq!(cache.total_bytes(), 10);
+    }
+
+    #[test]
+    fn test_replacing_an_entry_updates_the_size() {
+        let mut cache = LruCache::new(100);
+        cache.insert(0, vec![0u8; 10], 10);
+        cache.insert(0, vec![0u8; 40], 40);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.total_bytes(), 40);
+        assert_eq!(cache.remove(&0).map(|value| value.len()), Some(40));
+        assert!(cache.is_empty());
+        assert_eq!(cache.total_bytes(), 0);
+    }
+}
@@ -4,21 +4,34 @@
 /// systemd service ic-os/guestos/rootfs/etc/systemd/system/ic-canister-http-adapter.service
 /// systemd socket ic-os/guestos/rootfs/etc/systemd/system/ic-canister-http-adapter.socket
 use clap::Clap;
-use hyper::Client;
-use hyper_tls::HttpsConnector;
 use ic_async_utils::{
-    ensure_single_systemd_socket, incoming_from_first_systemd_socket, incoming_from_path,
+    ensure_single_systemd_socket, incoming_from_first_systemd_socket, incoming_from_path_with_mode,
+    incoming_from_tcp_listener, shutdown_signal,
+};
+use ic_canister_http_adapter::{
+    build_grpc_server, build_https_client, config_to_string, health_service_for, CanisterHttp, Cli,
+    IncomingSource, ListenAddress,
 };
-use ic_canister_http_adapter::{CanisterHttp, Cli, IncomingSource};
 use ic_canister_http_adapter_service::http_adapter_server::HttpAdapterServer;
 use ic_logger::{error, info, new_replica_logger_from_config};
-use serde_json::to_string_pretty;
-use tonic::transport::Server;
 
 #[tokio::main]
 pub async fn main() {
     let cli = Cli::parse();
 
+    if cli.check_config {
+        match cli.get_config() {
+            Ok(config) => {
+                println!("{}", config_to_string(&config));
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("The config is invalid: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let config = match cli.get_config() {
         Ok(config) => config,
         Err(err) => {
@@ -28,35 +41,58 @@ pub async fn main() {
 
     let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
 
-    if config.incoming_source == IncomingSource::Systemd {
+    if cli.listen.is_none() && config.incoming_source == IncomingSource::Systemd {
         // make sure we receive only one socket from systemd
-        ensure_single_systemd_socket();
+        if let Err(err) = ensure_single_systemd_socket() {
+            error!(logger, "{}", err);
+            // Flush the log before exiting.
+            drop(_async_log_guard);
+            std::process::exit(1);
+        }
     }
 
     info!(
         logger,
         "Starting the adapter with config: {}",
-        to_string_pretty(&config).unwrap()
+        config_to_string(&config)
     );
 
-    // HTTPS connector
-    let mut https = HttpsConnector::new();
-    https.https_only(true);
-    let https_client = Client::builder().build::<_, hyper::Body>(https);
-
-    let canister_http = CanisterHttp::new(https_client, logger.clone());
-    match config.incoming_source {
-        IncomingSource::Path(uds_path) => Server::builder()
-            .add_service(HttpAdapterServer::new(canister_http))
-            .serve_with_incoming(incoming_from_path(uds_path))
-            .await
-            .map_err(|e| error!(logger, "Canister Http adapter crashed: {}", e))
-            .expect("gRPC server crashed"),
-        IncomingSource::Systemd => Server::builder()
-            .add_service(HttpAdapterServer::new(canister_http))
-            .serve_with_incoming(incoming_from_first_systemd_socket())
-            .await
-            .map_err(|e| error!(logger, "Canister Http adapter crashed: {}", e))
-            .expect("gRPC server crashed"),
-    };
+    let https_client = build_https_client(&config);
+
+    let canister_http = CanisterHttp::new(https_client, logger.clone(), &config);
+    let adapter_service = HttpAdapterServer::new(canister_http);
+    // The client is initialized, so the adapter is ready to serve requests.
+    let health_service = health_service_for(&adapter_service).await;
+    let router = build_grpc_server(&config)
+        .add_service(health_service)
+        .add_service(adapter_service);
+    match (cli.listen, config.incoming_source) {
+        (Some(ListenAddress::Tcp(addr)), _) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .expect("Failed to bind the listen address");
+            router
+                .serve_with_incoming(incoming_from_tcp_listener(listener))
+                .await
+        }
+        (Some(ListenAddress::Path(uds_path)), _) | (None, IncomingSource::Path(uds_path)) => {
+            let incoming = incoming_from_path_with_mode(uds_path, config.socket_mode)
+                .expect("Failed to bind the socket path");
+            // Shutting down gracefully drops the incoming stream, which removes
+            // the socket file.
+            router
+                .serve_with_incoming_shutdown(
+                    incoming,
+                    shutdown_signal(logger.inner_logger.root.clone()),
+                )
+                .await
+        }
+        (None, IncomingSource::Systemd) => {
+            router
+                .serve_with_incoming(incoming_from_first_systemd_socket())
+                .await
+        }
+    }
+    .map_err(|e| error!(logger, "Canister Http adapter crashed: {}", e))
+    .expect("gRPC server crashed");
 }
@@ -0,0 +1,72 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Remembers the nonces seen within a sliding time window, so that replayed
+/// requests can be detected.
+pub struct NonceCache {
+    window: Duration,
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<u64>,
+    /// The nonces in the order they were recorded, used for expiry.
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl NonceCache {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(SeenNonces::default()),
+        }
+    }
+
+    /// Records `nonce`. Returns `false` if the nonce was already seen within the
+    /// window, i.e. the request is a replay.
+    pub fn insert(&self, nonce: u64) -> bool {
+        self.insert_at(nonce, Instant::now())
+    }
+
+    fn insert_at(&self, nonce: u64, now: Instant) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        while let Some(&(recorded_at, expired)) = seen.order.front() {
+            if now.saturating_duration_since(recorded_at) < self.window {
+                break;
+            }
+            seen.order.pop_front();
+            seen.nonces.remove(&expired);
+        }
+        if !seen.nonces.insert(nonce) {
+            return false;
+        }
+        seen.order.push_back((now, nonce));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_within_window_is_rejected() {
+        let cache = NonceCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(cache.insert_at(1, now));
+        assert!(!cache.insert_at(1, now + Duration::from_secs(5)));
+        assert!(cache.insert_at(2, now + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_nonce_expires_after_window() {
+        let cache = NonceCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(cache.insert_at(1, now));
+        assert!(cache.insert_at(1, now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+/// Limits the rate of requests per key with a token bucket for each key. A
+/// bucket holds up to `burst` tokens and is refilled with `rate` tokens per
+/// second. Each request takes one token.
+pub struct RateLimiter {
+    rate: u64,
+    burst: u64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter. A `rate` of zero disables it.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`. Returns `false` if the bucket
+    /// is empty, i.e. the request exceeds the limit.
+    pub fn try_acquire(&self, key: &str) -> bool {
+        self.try_acquire_at(key, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: &str, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let burst = self.burst.max(1) as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate as f64).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_throttles_once_depleted_and_refills() {
+        let limiter = RateLimiter::new(2, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at("a", now));
+        }
+        assert!(!limiter.try_acquire_at("a", now));
+        // Other keys have their own bucket.
+        assert!(limiter.try_acquire_at("b", now));
+
+        // At two tokens per second, one token is back after half a second.
+        assert!(limiter.try_acquire_at("a", now + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire_at("a", now + Duration::from_millis(500)));
+
+        // The bucket never holds more than the burst.
+        let later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at("a", later));
+        }
+        assert!(!limiter.try_acquire_at("a", later));
+    }
+
+    #[test]
+    fn test_zero_rate_disables_limiter() {
+        let limiter = RateLimiter::new(0, 0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.try_acquire_at("a", now));
+        }
+    }
+}
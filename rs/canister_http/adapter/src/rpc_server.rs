@@ -1,20 +1,349 @@
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    client::PeerCertificate,
+    config::Config,
+    keyed_lock::KeyedLock,
+    lru_cache::LruCache,
+    nonce_cache::NonceCache,
+    rate_limiter::RateLimiter,
+    transform::{IdentityTransform, ResponseTransform},
+};
+use brotli2::read::BrotliDecoder;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING,
+    CONTENT_LENGTH, COOKIE, TRAILER, USER_AGENT, VARY,
+};
 use http::Uri;
 use hyper::client::connect::Connect;
-use hyper::{body, Body, Client, Method};
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body, Client, Method,
+};
 use ic_canister_http_adapter_service::http_adapter_server::HttpAdapter;
-use ic_logger::{debug, ReplicaLogger};
+use ic_logger::{debug, info, new_logger, trace, ReplicaLogger};
 use ic_protobuf::canister_http::v1::{CanisterHttpRequest, CanisterHttpResponse, HttpHeader};
-use tonic::{Request, Response, Status};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    time::timeout,
+};
+use tonic::{
+    metadata::MetadataValue,
+    transport::{NamedService, Server},
+    Request, Response, Status,
+};
+use tonic_health::server::{Health, HealthServer};
+
+/// The key of the gRPC request metadata holding the ID of the canister that
+/// makes the request.
+pub const CANISTER_ID_METADATA_KEY: &str = "canister-id";
+
+/// The key of the gRPC request metadata holding the idempotency key of the
+/// request. Requests that repeat the key of a recent request are answered
+/// with the response to that request instead of being sent again.
+pub const IDEMPOTENCY_KEY_METADATA_KEY: &str = "idempotency-key";
+
+/// The key of the gRPC response metadata holding the DER-encoded leaf
+/// certificate of the server, if the request asked for it with
+/// `return_peer_cert`.
+pub const PEER_CERTIFICATE_METADATA_KEY: &str = "peer-certificate-bin";
+
+/// The key of the gRPC metadata holding the ID that correlates the log lines
+/// of a request. It is taken from the request, or generated if absent, and
+/// returned in the response.
+pub const REQUEST_ID_METADATA_KEY: &str = "x-request-id";
+
+/// The number of bytes of response bodies that are logged if `trace_http` is
+/// set.
+const TRACE_BODY_PREVIEW_BYTES: usize = 1024;
+
+/// The headers whose values are not logged if `trace_http` is set.
+const REDACTED_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Builds the gRPC server that serves the requests of the replica, with the
+/// HTTP/2 keep-alive tuned according to `config`.
+pub fn build_grpc_server(config: &Config) -> Server {
+    Server::builder()
+        .http2_keepalive_interval(
+            config
+                .http2_keepalive_interval_secs
+                .map(Duration::from_secs),
+        )
+        .http2_keepalive_timeout(config.http2_keepalive_timeout_secs.map(Duration::from_secs))
+}
+
+/// Returns the standard gRPC health service (`grpc.health.v1.Health`),
+/// reporting `service` as serving. It should be created once the adapter is
+/// ready to serve requests.
+pub async fn health_service_for<S: NamedService>(_service: &S) -> HealthServer<impl Health> {
+    let (mut reporter, health_service) = tonic_health::server::health_reporter();
+    reporter.set_serving::<S>().await;
+    health_service
+}
 
 /// implements RPC
 pub struct CanisterHttp<C: Clone + Connect + Send + Sync + 'static> {
     client: Client<C>,
     logger: ReplicaLogger,
+    config: Config,
+    nonces: NonceCache,
+    circuit_breaker: CircuitBreaker,
+    rate_limiter: RateLimiter,
+    injected_headers: HeaderMap,
+    user_agent: HeaderValue,
+    /// Bounds the number of concurrent requests, if set.
+    concurrency: Option<Semaphore>,
+    /// The responses to requests with an idempotency key, keyed by the
+    /// canister and the key, with the time they were received and the
+    /// fingerprint of the request.
+    idempotent_responses: Mutex<LruCache<IdempotencyKey, (Instant, u64, CanisterHttpResponse)>>,
+    /// Makes requests with the same idempotency key wait for each other, so
+    /// that only the first one is sent.
+    idempotent_requests: KeyedLock<IdempotencyKey>,
+    /// The responses to GET requests that may be reused according to their
+    /// `Cache-Control` header, with the time they expire.
+    fresh_responses: Mutex<LruCache<ResponseCacheKey, (Instant, CanisterHttpResponse)>>,
+    transform: Box<dyn ResponseTransform>,
+}
+
+/// The canister that made a request, if known, and the idempotency key of the
+/// request.
+type IdempotencyKey = (Option<String>, String);
+
+/// Identifies the cached response to a GET request. A response is only reused
+/// for a request of the same canister with the same headers.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ResponseCacheKey {
+    canister_id: String,
+    url: String,
+    decompress: bool,
+    /// The headers of the request, sorted by name and value.
+    headers: Vec<(String, Vec<u8>)>,
 }
 
 impl<C: Clone + Connect + Send + Sync + 'static> CanisterHttp<C> {
-    pub fn new(client: Client<C>, logger: ReplicaLogger) -> Self {
-        Self { client, logger }
+    pub fn new(client: Client<C>, logger: ReplicaLogger, config: &Config) -> Self {
+        Self {
+            client,
+            logger,
+            config: config.clone(),
+            nonces: NonceCache::new(Duration::from_secs(config.replay_window_secs)),
+            circuit_breaker: CircuitBreaker::new(
+                config.circuit_breaker_failure_threshold,
+                Duration::from_secs(config.circuit_breaker_window_secs),
+                Duration::from_secs(config.circuit_breaker_cooldown_secs),
+            ),
+            rate_limiter: RateLimiter::new(
+                config.canister_rate_limit_per_sec,
+                config.canister_rate_limit_burst,
+            ),
+            injected_headers: config
+                .injected_header_map()
+                .expect("The injected headers are validated when the config is loaded"),
+            user_agent: config
+                .user_agent_header()
+                .expect("The user agent is validated when the config is loaded"),
+            concurrency: if config.max_concurrent_requests > 0 {
+                Some(Semaphore::new(config.max_concurrent_requests))
+            } else {
+                None
+            },
+            idempotent_responses: Mutex::new(LruCache::new(config.idempotency_cache_bytes)),
+            idempotent_requests: KeyedLock::new(),
+            fresh_responses: Mutex::new(LruCache::new(config.max_cache_bytes)),
+            transform: Box::new(IdentityTransform),
+        }
+    }
+
+    /// Applies `transform` to every response before it is returned.
+    pub fn with_response_transform<T: ResponseTransform + 'static>(self, transform: T) -> Self {
+        Self {
+            transform: Box::new(transform),
+            ..self
+        }
+    }
+
+    /// Returns the response to the recent request with the idempotency key
+    /// `key`, if any. Fails if that request had a different `fingerprint`.
+    fn cached_response(
+        &self,
+        key: &IdempotencyKey,
+        fingerprint: u64,
+    ) -> Result<Option<CanisterHttpResponse>, Status> {
+        let ttl = Duration::from_secs(self.config.idempotency_ttl_secs);
+        let mut responses = self.idempotent_responses.lock().unwrap();
+        if let Some((received_at, cached_fingerprint, response)) = responses.get(key) {
+            if received_at.elapsed() < ttl {
+                if *cached_fingerprint != fingerprint {
+                    return Err(Status::failed_precondition(
+                        "Idempotency key was already used for a different request",
+                    ));
+                }
+                return Ok(Some(response.clone()));
+            }
+            responses.remove(key);
+        }
+        Ok(None)
+    }
+
+    /// Keeps the response to the request with the idempotency key `key`.
+    fn cache_response(
+        &self,
+        key: IdempotencyKey,
+        fingerprint: u64,
+        response: &CanisterHttpResponse,
+    ) {
+        self.idempotent_responses.lock().unwrap().insert(
+            key,
+            (Instant::now(), fingerprint, response.clone()),
+            response_size(response),
+        );
+    }
+
+    /// Returns the cached response to a GET request of `key`, if it has not
+    /// expired yet.
+    fn fresh_response(&self, key: &ResponseCacheKey) -> Option<CanisterHttpResponse> {
+        let mut responses = self.fresh_responses.lock().unwrap();
+        if let Some((expires_at, response)) = responses.get(key) {
+            if Instant::now() < *expires_at {
+                return Some(response.clone());
+            }
+            responses.remove(key);
+        }
+        None
+    }
+
+    /// Takes one of the slots for concurrent requests, waiting up to the
+    /// configured queue timeout for one to become free.
+    async fn acquire_slot<'a>(
+        &self,
+        semaphore: &'a Semaphore,
+        logger: &ReplicaLogger,
+    ) -> Result<SemaphorePermit<'a>, Status> {
+        let queue_timeout = Duration::from_secs(self.config.concurrency_queue_timeout_secs);
+        let permit = if queue_timeout.is_zero() {
+            semaphore.try_acquire().ok()
+        } else {
+            timeout(queue_timeout, semaphore.acquire())
+                .await
+                .ok()
+                .and_then(Result::ok)
+        };
+        permit.ok_or_else(|| {
+            debug!(logger, "Rejected request over the concurrency limit");
+            Status::resource_exhausted("Too many concurrent requests")
+        })
+    }
+
+    /// Sends the request and reads the whole response. Fails if the host does
+    /// not respond in time.
+    async fn fetch(
+        &self,
+        http_req: hyper::Request<Body>,
+        logger: &ReplicaLogger,
+    ) -> Result<(http::response::Parts, Vec<u8>), Status> {
+        let http_resp = timeout(
+            Duration::from_secs(self.config.time_to_first_byte_timeout_secs),
+            self.client.request(http_req),
+        )
+        .await
+        .map_err(|_| {
+            debug!(logger, "Timed out waiting for the response headers");
+            Status::new(
+                tonic::Code::DeadlineExceeded,
+                "Timed out waiting for the first byte of the response",
+            )
+        })?
+        .map_err(|err| {
+            debug!(logger, "Failed to connect: {}", err);
+            Status::new(tonic::Code::Unavailable, "Failed to connect")
+        })?;
+
+        let (parts, body) = http_resp.into_parts();
+        self.check_header_limits(&parts.headers, logger)?;
+        let content = self.read_body(body, logger).await?;
+        Ok((parts, content))
+    }
+
+    /// Fails if the response carries more headers, or more header bytes, than
+    /// configured.
+    fn check_header_limits(
+        &self,
+        headers: &HeaderMap,
+        logger: &ReplicaLogger,
+    ) -> Result<(), Status> {
+        if headers.len() > self.config.max_response_headers {
+            debug!(logger, "Rejected response with {} headers", headers.len());
+            return Err(Status::out_of_range(format!(
+                "Response headers exceed the limit of {} headers",
+                self.config.max_response_headers
+            )));
+        }
+        let header_bytes = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+        if header_bytes > self.config.max_response_header_bytes {
+            debug!(
+                logger,
+                "Rejected response with {} header bytes", header_bytes
+            );
+            return Err(Status::out_of_range(format!(
+                "Response headers exceed the limit of {} bytes",
+                self.config.max_response_header_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads the whole response body. Fails if no data arrives for longer than
+    /// the read idle timeout, or if the body exceeds the response size limit.
+    async fn read_body(&self, mut body: Body, logger: &ReplicaLogger) -> Result<Vec<u8>, Status> {
+        let read_idle_timeout = Duration::from_secs(self.config.read_idle_timeout_secs);
+        let limit = self.config.http_request_size_limit_bytes;
+        let mut content = Vec::new();
+        loop {
+            match timeout(read_idle_timeout, body.data()).await {
+                Ok(Some(Ok(chunk))) => {
+                    if (content.len() + chunk.len()) as u64 > limit {
+                        debug!(logger, "Rejected body of over {} bytes", limit);
+                        return Err(Status::out_of_range(format!(
+                            "Response body exceeds the limit of {} bytes",
+                            limit
+                        )));
+                    }
+                    content.extend_from_slice(&chunk)
+                }
+                Ok(Some(Err(err))) => {
+                    debug!(logger, "Failed to fetch body: {}", err);
+                    return Err(Status::new(
+                        tonic::Code::Unavailable,
+                        "Failed to fetch body",
+                    ));
+                }
+                Ok(None) => return Ok(content),
+                Err(_) => {
+                    debug!(logger, "Timed out waiting for the response body");
+                    return Err(Status::new(
+                        tonic::Code::DeadlineExceeded,
+                        "Timed out waiting for more data of the response body",
+                    ));
+                }
+            }
+        }
     }
 }
 
@@ -24,32 +353,343 @@ impl<C: Clone + Connect + Send + Sync + 'static> HttpAdapter for CanisterHttp<C>
         &self,
         request: Request<CanisterHttpRequest>,
     ) -> Result<Response<CanisterHttpResponse>, Status> {
+        let start = Instant::now();
+
+        let request_id = request
+            .metadata()
+            .get(REQUEST_ID_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+        let logger = new_logger!(self.logger; canister_http.request_id => request_id.clone());
+
+        let canister_id = request
+            .metadata()
+            .get(CANISTER_ID_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let idempotency_key = request
+            .metadata()
+            .get(IDEMPOTENCY_KEY_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(|key| (canister_id.clone(), key.to_string()));
+
         let req = request.into_inner();
 
+        let fingerprint = request_fingerprint(&req);
+        // Held until the response is cached, so that a duplicate sent in the
+        // meantime is answered with it.
+        let _idempotent_request = match &idempotency_key {
+            Some(key) => Some(self.idempotent_requests.lock(key.clone()).await),
+            None => None,
+        };
+        if let Some(key) = &idempotency_key {
+            let cached = self.cached_response(key, fingerprint).map_err(|status| {
+                debug!(
+                    logger,
+                    "Rejected reuse of idempotency key {} for a different request", key.1
+                );
+                status
+            })?;
+            if let Some(response) = cached {
+                debug!(
+                    logger,
+                    "Answered request with idempotency key {} from the cache", key.1
+                );
+                return Ok(response_with_request_id(response, &request_id));
+            }
+        }
+
+        let _slot = match &self.concurrency {
+            Some(semaphore) => Some(self.acquire_slot(semaphore, &logger).await?),
+            None => None,
+        };
+
+        // Requests without a canister ID are not rate limited.
+        if let Some(canister_id) = &canister_id {
+            if !self.rate_limiter.try_acquire(canister_id) {
+                debug!(
+                    logger,
+                    "Rejected request of canister {} over the rate limit", canister_id
+                );
+                return Err(Status::resource_exhausted(
+                    "Canister exceeded its request rate limit",
+                ));
+            }
+        }
+
+        if req.url.len() > self.config.max_url_length {
+            debug!(logger, "Rejected URL of {} bytes", req.url.len());
+            return Err(Status::invalid_argument(format!(
+                "Url exceeds the limit of {} bytes",
+                self.config.max_url_length
+            )));
+        }
+
         let uri = req.url.parse::<Uri>().map_err(|err| {
-            debug!(self.logger, "Failed to parse URL: {}", err);
+            debug!(logger, "Failed to parse URL: {}", err);
             Status::new(tonic::Code::InvalidArgument, "Failed to parse url")
         })?;
+        if let Err(reason) = validate_url(&uri) {
+            debug!(logger, "Rejected invalid URL {}: {}", uri, reason);
+            return Err(Status::invalid_argument(reason));
+        }
 
-        // TODO: Connect to SOCKS proxy (NET-881)
-        let http_req = hyper::Request::builder()
-            .method(Method::GET)
-            .uri(uri)
-            .body(Body::from(req.body))
-            .map_err(|err| {
-                debug!(self.logger, "Failed to build HTTP request URL: {}", err);
-                Status::new(tonic::Code::InvalidArgument, "Failed to build http request")
-            })?;
+        if self.config.require_https && uri.scheme_str() != Some("https") {
+            debug!(logger, "Rejected non-HTTPS URL: {}", uri);
+            return Err(Status::invalid_argument("Url scheme must be https"));
+        }
 
-        let http_resp = self.client.request(http_req).await.map_err(|err| {
-            debug!(self.logger, "Failed to connect: {}", err);
-            Status::new(tonic::Code::Unavailable, "Failed to connect")
+        // IP literals are not resolved, so the resolver cannot filter them.
+        let literal_ip = uri
+            .host()
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+            .and_then(|host| host.parse::<IpAddr>().ok());
+        if let Some(ip) = literal_ip {
+            if !self.config.ip_family.allows(&ip) {
+                debug!(logger, "Rejected URL with disallowed IP: {}", ip);
+                return Err(Status::invalid_argument(
+                    "Url host is not of the allowed IP family",
+                ));
+            }
+        }
+
+        let method = match req.method.as_deref() {
+            Some(method) => {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes()).map_err(|err| {
+                    debug!(logger, "Failed to parse method: {}", err);
+                    Status::invalid_argument("Failed to parse method")
+                })?
+            }
+            None => Method::GET,
+        };
+        if !self.config.allowed_methods.is_empty()
+            && !self
+                .config
+                .allowed_methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method.as_str()))
+        {
+            debug!(logger, "Rejected disallowed method: {}", method);
+            return Err(Status::permission_denied("Method is not allowed"));
+        }
+
+        if req.body.len() as u64 > self.config.max_request_bytes {
+            debug!(logger, "Rejected request body of {} bytes", req.body.len());
+            return Err(Status::invalid_argument(format!(
+                "Request body exceeds the limit of {} bytes",
+                self.config.max_request_bytes
+            )));
+        }
+
+        let decompress = req
+            .decompress_response
+            .unwrap_or(self.config.decompress_responses);
+
+        let mut headers = parse_headers(&req.headers).map_err(|err| {
+            debug!(logger, "Failed to parse headers: {}", err);
+            Status::invalid_argument("Failed to parse headers")
         })?;
+        for (name, value) in self.injected_headers.iter() {
+            // Headers set by the canister win.
+            if !headers.contains_key(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        if !headers.contains_key(USER_AGENT) {
+            headers.insert(USER_AGENT, self.user_agent.clone());
+        }
+
+        let trailers = if req.trailers.is_empty() {
+            None
+        } else {
+            let trailers = parse_headers(&req.trailers).map_err(|err| {
+                debug!(logger, "Failed to parse trailers: {}", err);
+                Status::invalid_argument("Failed to parse trailers")
+            })?;
+            // Announce the trailer fields; only announced fields are sent.
+            let names = trailers
+                .keys()
+                .map(HeaderName::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            headers.insert(
+                TRAILER,
+                HeaderValue::from_str(&names).expect("Header names are valid header values"),
+            );
+            Some(trailers)
+        };
 
-        let status = http_resp.status().as_u16() as u32;
+        // The nonce is only spent by requests that passed validation, so that
+        // a rejected request may be corrected and sent again.
+        if let Some(nonce) = req.nonce {
+            if !self.nonces.insert(nonce) {
+                debug!(logger, "Rejected replayed request with nonce {}", nonce);
+                return Err(Status::new(
+                    tonic::Code::AlreadyExists,
+                    "Request nonce was already used",
+                ));
+            }
+        }
 
-        let headers = http_resp
-            .headers()
+        // Only the responses to GET requests of known canisters are cached, so
+        // that canisters do not share them. Cached responses carry no peer
+        // certificate, so requests for one bypass the cache, as do requests
+        // with credentials.
+        let cache_key = match &canister_id {
+            Some(canister_id)
+                if self.config.max_cache_bytes > 0
+                    && method == Method::GET
+                    && !req.return_peer_cert
+                    && !headers.contains_key(AUTHORIZATION)
+                    && !headers.contains_key(COOKIE) =>
+            {
+                let mut cache_headers = headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                    .collect::<Vec<_>>();
+                cache_headers.sort();
+                Some(ResponseCacheKey {
+                    canister_id: canister_id.clone(),
+                    url: req.url.clone(),
+                    decompress,
+                    headers: cache_headers,
+                })
+            }
+            _ => None,
+        };
+        if let Some(response) = cache_key.as_ref().and_then(|key| self.fresh_response(key)) {
+            debug!(logger, "Answered request to {} from the cache", req.url);
+            return Ok(response_with_request_id(response, &request_id));
+        }
+
+        let body = Bytes::from(req.body);
+
+        // The request is built anew for every attempt, as sending it consumes
+        // the body.
+        let build_request = || {
+            let mut builder = hyper::Request::builder()
+                .method(method.clone())
+                .uri(uri.clone());
+            if let Some(builder_headers) = builder.headers_mut() {
+                builder_headers.extend(headers.clone());
+            }
+            let body = match &trailers {
+                Some(trailers) => chunked_body_with_trailers(body.clone(), trailers.clone()),
+                None => Body::from(body.clone()),
+            };
+            // TODO: Connect to SOCKS proxy (NET-881)
+            builder.body(body).map_err(|err| {
+                debug!(logger, "Failed to build HTTP request URL: {}", err);
+                Status::new(tonic::Code::InvalidArgument, "Failed to build http request")
+            })
+        };
+
+        if self.config.trace_http {
+            trace!(
+                logger,
+                "Sending {} {} with headers [{}]",
+                method,
+                uri,
+                format_headers_redacted(&headers)
+            );
+        }
+
+        let host = uri
+            .authority()
+            .map(|authority| authority.to_string())
+            .unwrap_or_default();
+        // Only idempotent requests are retried.
+        let max_retries = if method == Method::GET || method == Method::HEAD {
+            self.config.max_retries
+        } else {
+            0
+        };
+        let mut backoff = Duration::from_millis(self.config.retry_backoff_millis);
+        let mut attempt = 0;
+        // Bounds all attempts together with the backoffs between them.
+        let deadline = self
+            .config
+            .total_deadline_secs
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let deadline_exceeded = || {
+            debug!(
+                logger,
+                "Exceeded the total deadline of the request to {}", host
+            );
+            Status::deadline_exceeded("Exceeded the total deadline of the request")
+        };
+        let (parts, mut content) = loop {
+            if !self.circuit_breaker.allow(&host) {
+                debug!(logger, "Rejected request to failing host {}", host);
+                return Err(Status::unavailable("Circuit breaker is open for the host"));
+            }
+            let fetch = self.fetch(build_request()?, &logger);
+            let result = match deadline {
+                Some(deadline) => {
+                    match timeout(deadline.saturating_duration_since(Instant::now()), fetch).await {
+                        Ok(result) => result,
+                        Err(_) => return Err(deadline_exceeded()),
+                    }
+                }
+                None => fetch.await,
+            };
+            // Only transport errors and server errors count against the host,
+            // local rejections such as of oversized responses do not.
+            let (host_failed, retry) = match &result {
+                Ok((parts, _)) => (
+                    parts.status.is_server_error(),
+                    parts.status.is_server_error(),
+                ),
+                Err(status) => (
+                    matches!(
+                        status.code(),
+                        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+                    ),
+                    // Connection errors are reported as unavailable.
+                    status.code() == tonic::Code::Unavailable,
+                ),
+            };
+            if host_failed {
+                self.circuit_breaker.record_failure(&host);
+            } else if result.is_ok() {
+                self.circuit_breaker.record_success(&host);
+            }
+            if !retry || attempt >= max_retries {
+                break result?;
+            }
+            if deadline.map_or(false, |deadline| Instant::now() + backoff >= deadline) {
+                return Err(deadline_exceeded());
+            }
+            attempt += 1;
+            debug!(
+                logger,
+                "Retrying request to {} in {:?}, attempt {} of {}",
+                host,
+                backoff,
+                attempt,
+                max_retries
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        };
+
+        let status = parts.status.as_u16() as u32;
+        let peer_certificate = if req.return_peer_cert {
+            parts.extensions.get::<PeerCertificate>().cloned()
+        } else {
+            None
+        };
+
+        let content_encoding = parts
+            .headers
+            .get(CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_ascii_lowercase());
+
+        let mut headers = parts
+            .headers
             .iter()
             .map(|(k, v)| HttpHeader {
                 name: k.to_string(),
@@ -57,16 +697,220 @@ impl<C: Clone + Connect + Send + Sync + 'static> HttpAdapter for CanisterHttp<C>
             })
             .collect::<Vec<HttpHeader>>();
 
-        // TODO: replace this with a bounded version with timeout. (NET-882)
-        let body_bytes = body::to_bytes(http_resp).await.map_err(|err| {
-            debug!(self.logger, "Failed to fetch body: {}", err);
-            Status::new(tonic::Code::Unavailable, "Failed to fetch body")
-        })?;
+        if decompress {
+            let limit = self.config.http_request_size_limit_bytes;
+            if let Some(decoded) = content_encoding
+                .as_deref()
+                .and_then(|encoding| decompress_body(encoding, &content, limit))
+            {
+                content = decoded.map_err(|err| {
+                    debug!(logger, "Failed to decompress body: {}", err);
+                    Status::new(tonic::Code::Unavailable, "Failed to decompress body")
+                })?;
+                if content.len() as u64 > limit {
+                    debug!(
+                        logger,
+                        "Rejected body decompressing to over {} bytes", limit
+                    );
+                    return Err(Status::out_of_range(format!(
+                        "Decompressed response body exceeds the limit of {} bytes",
+                        limit
+                    )));
+                }
+                // The encoding and the length of the body no longer apply.
+                headers.retain(|header| {
+                    header.name != CONTENT_ENCODING.as_str()
+                        && header.name != CONTENT_LENGTH.as_str()
+                });
+            }
+        }
+
+        if self.config.sort_response_headers {
+            // The sort is stable, so the values of a header keep their order.
+            headers.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+
+        if self.config.trace_http {
+            let preview = &content[..content.len().min(TRACE_BODY_PREVIEW_BYTES)];
+            trace!(
+                logger,
+                "Received status {} with headers [{}] and {} body bytes starting with {:?}",
+                status,
+                format_headers_redacted(&parts.headers),
+                content.len(),
+                String::from_utf8_lossy(preview)
+            );
+        }
 
-        Ok(Response::new(CanisterHttpResponse {
+        info!(
+            logger,
+            "Request completed";
+            canister_http.url => req.url,
+            canister_http.status => status,
+            canister_http.latency_ms => start.elapsed().as_millis() as u64,
+        );
+
+        // Responses that vary with the request headers are not cached.
+        let max_age = if (200..300).contains(&status) && !parts.headers.contains_key(VARY) {
+            cache_max_age(&parts.headers)
+        } else {
+            None
+        };
+        let response = self.transform.transform(CanisterHttpResponse {
             status,
             headers,
-            content: body_bytes.to_vec(),
-        }))
+            content,
+        });
+        if let (Some(key), Some(max_age)) = (cache_key, max_age) {
+            let size = response_size(&response);
+            self.fresh_responses.lock().unwrap().insert(
+                key,
+                (Instant::now() + max_age, response.clone()),
+                size,
+            );
+        }
+        if let Some(key) = idempotency_key {
+            self.cache_response(key, fingerprint, &response);
+        }
+        let mut response = response_with_request_id(response, &request_id);
+        if let Some(PeerCertificate(der)) = peer_certificate {
+            response.metadata_mut().insert_bin(
+                PEER_CERTIFICATE_METADATA_KEY,
+                MetadataValue::from_bytes(&der),
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// Wraps `response`, returning `request_id` in its metadata.
+fn response_with_request_id(
+    response: CanisterHttpResponse,
+    request_id: &str,
+) -> Response<CanisterHttpResponse> {
+    let mut response = Response::new(response);
+    if let Ok(value) = request_id.parse() {
+        response
+            .metadata_mut()
+            .insert(REQUEST_ID_METADATA_KEY, value);
+    }
+    response
+}
+
+/// Returns a hash of the parts of `req` that must match for a response to be
+/// reused under the same idempotency key.
+fn request_fingerprint(req: &CanisterHttpRequest) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    req.url.hash(&mut hasher);
+    req.method
+        .as_deref()
+        .unwrap_or("GET")
+        .to_ascii_uppercase()
+        .hash(&mut hasher);
+    req.body.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the approximate size of `response` in bytes.
+fn response_size(response: &CanisterHttpResponse) -> usize {
+    response.content.len()
+        + response
+            .headers
+            .iter()
+            .map(|header| header.name.len() + header.value.len())
+            .sum::<usize>()
+}
+
+/// Returns how long a response may be reused according to the `max-age`
+/// directive of its `Cache-Control` header. Returns `None` if the response
+/// must not be reused.
+fn cache_max_age(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    for value in headers.get_all(CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                _ => {
+                    if let Some(secs) = directive.strip_prefix("max-age=") {
+                        max_age = secs.trim_matches('"').parse().ok();
+                    }
+                }
+            }
+        }
     }
+    max_age.filter(|secs| *secs > 0).map(Duration::from_secs)
+}
+
+/// Checks that `uri` has a host and an HTTP or HTTPS scheme, which are
+/// required to dispatch the request. Returns the reason otherwise.
+fn validate_url(uri: &Uri) -> Result<(), String> {
+    if uri.host().map_or(true, str::is_empty) {
+        return Err("Url has no host".to_string());
+    }
+    match uri.scheme_str() {
+        Some("http") | Some("https") => Ok(()),
+        Some(scheme) => Err(format!("Url scheme {} is not supported", scheme)),
+        None => Err("Url has no scheme".to_string()),
+    }
+}
+
+/// Converts the headers or trailers of a gRPC request into a header map.
+fn parse_headers(fields: &[HttpHeader]) -> Result<HeaderMap, http::Error> {
+    let mut map = HeaderMap::new();
+    for field in fields {
+        map.append(
+            HeaderName::from_bytes(field.name.as_bytes())?,
+            HeaderValue::from_bytes(&field.value)?,
+        );
+    }
+    Ok(map)
+}
+
+/// Formats `headers` for logging, replacing the values of the headers that
+/// carry credentials.
+fn format_headers_redacted(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            if REDACTED_HEADERS.contains(&name.as_str()) {
+                format!("{}: <redacted>", name)
+            } else {
+                format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns a streaming body that sends `body` followed by `trailers`. As its
+/// length is not known upfront, it is sent with the chunked transfer coding.
+fn chunked_body_with_trailers(body: Bytes, trailers: HeaderMap) -> Body {
+    let (mut sender, chunked) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(body).await.is_ok() {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    chunked
+}
+
+/// Decompresses `body` according to the given `Content-Encoding` value,
+/// decoding at most one byte more than `limit`, so that a small body cannot
+/// expand into an arbitrarily large one. A result longer than `limit` means
+/// that the body exceeds it. Returns `None` if the encoding is not supported,
+/// in which case the body should be returned as received.
+fn decompress_body(encoding: &str, body: &[u8], limit: u64) -> Option<io::Result<Vec<u8>>> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(body)),
+        // The "deflate" content coding is the zlib format, see RFC 7230.
+        "deflate" => Box::new(ZlibDecoder::new(body)),
+        "br" => Box::new(BrotliDecoder::new(body)),
+        _ => return None,
+    };
+    let mut decoded = Vec::new();
+    let result = decoder
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decoded);
+    Some(result.map(|_| decoded))
 }
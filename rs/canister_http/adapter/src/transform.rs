@@ -0,0 +1,15 @@
+use ic_protobuf::canister_http::v1::CanisterHttpResponse;
+
+/// Post-processes the responses of outgoing requests before they are returned
+/// to the replica, e.g. to strip volatile fields so that the responses of all
+/// replicas agree. By default, responses are returned unchanged.
+pub trait ResponseTransform: Send + Sync {
+    fn transform(&self, response: CanisterHttpResponse) -> CanisterHttpResponse {
+        response
+    }
+}
+
+/// The transform that returns responses unchanged.
+pub struct IdentityTransform;
+
+impl ResponseTransform for IdentityTransform {}
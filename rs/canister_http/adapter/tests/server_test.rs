@@ -1,19 +1,53 @@
-use futures::TryFutureExt;
-use http::StatusCode;
+use brotli2::write::BrotliEncoder;
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use futures::{Future, TryFutureExt};
+use http::{
+    header::{CONTENT_ENCODING, USER_AGENT},
+    StatusCode,
+};
 use hyper::{
     client::{connect::Connect, HttpConnector},
-    Client,
+    server::conn::AddrStream,
+    service::make_service_fn,
+    Body, Client,
+};
+use ic_async_utils::{incoming_from_path_with_mode, incoming_from_tcp_listener};
+use ic_canister_http_adapter::{
+    build_client_builder, build_grpc_server, build_http_connector, build_https_client,
+    health_service_for, CanisterHttp, Config, FamilyResolver, IpFamily, PeerCertificateConnector,
+    ResponseTransform, TimeoutConnector, TlsHandshakeConnector, CANISTER_ID_METADATA_KEY,
+    IDEMPOTENCY_KEY_METADATA_KEY, PEER_CERTIFICATE_METADATA_KEY, REQUEST_ID_METADATA_KEY,
 };
-use hyper_tls::HttpsConnector;
-use ic_canister_http_adapter::{CanisterHttp, Config};
 use ic_canister_http_adapter_service::{
     http_adapter_client::HttpAdapterClient, http_adapter_server::HttpAdapterServer,
 };
-use ic_logger::{new_replica_logger_from_config, ReplicaLogger};
-use ic_protobuf::canister_http::v1::{CanisterHttpRequest, HttpHeader};
-use std::convert::TryFrom;
-use tokio::net::UnixStream;
+use ic_config::logger::{Config as LoggerConfig, LogFormat, LogTarget};
+use ic_logger::{new_replica_logger, new_replica_logger_from_config, ReplicaLogger};
+use ic_protobuf::canister_http::v1::{CanisterHttpRequest, CanisterHttpResponse, HttpHeader};
+use native_tls::TlsConnector;
+use std::{
+    convert::Infallible,
+    convert::TryFrom,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UnixStream},
+    sync::oneshot,
+};
 use tonic::transport::{Channel, Endpoint, Server, Uri};
+use tonic_health::proto::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
 use tower::service_fn;
 use unix::UnixListenerDrop;
 use uuid::Uuid;
@@ -24,7 +58,7 @@ async fn test_https() {
     let config = Config::default();
     let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
 
-    let canister_http = setup_grpc_server_with_https_client(logger.clone());
+    let canister_http = setup_grpc_server_with_https_client(logger.clone(), &config);
     let channel = setup_loop_channel_unix(canister_http).await;
 
     // create gRPC client that communicated with gRPC server through UDS channel
@@ -43,12 +77,61 @@ async fn test_https() {
     );
 }
 
+#[tokio::test]
+async fn test_return_peer_cert() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let canister_http = setup_grpc_server_with_https_client(logger.clone(), &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(CanisterHttpRequest {
+        return_peer_cert: true,
+        ..build_http_canister_request("https://www.google.com".to_string())
+    });
+    let response = client.send_http_request(request).await.unwrap();
+    let returned = response
+        .metadata()
+        .get_bin(PEER_CERTIFICATE_METADATA_KEY)
+        .expect("The peer certificate is missing")
+        .to_bytes()
+        .unwrap();
+
+    // Fetch the certificate of the server independently of the adapter.
+    let tcp = tokio::net::TcpStream::connect("www.google.com:443")
+        .await
+        .unwrap();
+    let tls = tokio_native_tls::TlsConnector::from(TlsConnector::new().unwrap())
+        .connect("www.google.com", tcp)
+        .await
+        .unwrap();
+    let expected = tls
+        .get_ref()
+        .peer_certificate()
+        .unwrap()
+        .unwrap()
+        .to_der()
+        .unwrap();
+    assert_eq!(&returned[..], &expected[..]);
+
+    // The certificate is only returned on request.
+    let request = tonic::Request::new(build_http_canister_request(
+        "https://www.google.com".to_string(),
+    ));
+    let response = client.send_http_request(request).await.unwrap();
+    assert!(response
+        .metadata()
+        .get_bin(PEER_CERTIFICATE_METADATA_KEY)
+        .is_none());
+}
+
 #[tokio::test]
 async fn test_http() {
     let config = Config::default();
     let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
 
-    let canister_http = setup_grpc_server_with_https_client(logger.clone());
+    let canister_http = setup_grpc_server_with_https_client(logger.clone(), &config);
     let channel = setup_loop_channel_unix(canister_http).await;
     let mut client = HttpAdapterClient::new(channel);
 
@@ -68,7 +151,7 @@ async fn test_no_http() {
     let config = Config::default();
     let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
 
-    let canister_http = setup_grpc_server_with_https_client(logger.clone());
+    let canister_http = setup_grpc_server_with_https_client(logger.clone(), &config);
     let channel = setup_loop_channel_unix(canister_http).await;
     let mut client = HttpAdapterClient::new(channel);
 
@@ -78,27 +161,1671 @@ async fn test_no_http() {
     assert!(response.is_err());
 }
 
-// TODO: increase functionality of this function (NET-883)
-fn build_http_canister_request(url: String) -> CanisterHttpRequest {
-    let headers = vec![HttpHeader {
-        name: "User-Agent".to_string(),
-        value: "test".as_bytes().to_vec(),
-    }];
+/// Asserts that the adapter decompresses a body of the given content coding.
+async fn assert_decompresses(encoding: &'static str, compressed: Vec<u8>) {
+    let config = Config {
+        decompress_responses: true,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
 
-    CanisterHttpRequest {
-        url,
-        body: "".to_string().into_bytes(),
-        headers,
+    let addr = spawn_test_server(move |_| {
+        let response = hyper::Response::builder()
+            .header(CONTENT_ENCODING, encoding)
+            .body(Body::from(compressed.clone()))
+            .unwrap();
+        async move { response }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client
+        .send_http_request(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, b"hello world".to_vec());
+    assert!(!response
+        .headers
+        .iter()
+        .any(|header| header.name == CONTENT_ENCODING.as_str()));
+}
+
+#[tokio::test]
+async fn test_decompress_gzip() {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"hello world").unwrap();
+    assert_decompresses("gzip", encoder.finish().unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_decompress_deflate() {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"hello world").unwrap();
+    assert_decompresses("deflate", encoder.finish().unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_decompress_brotli() {
+    let mut encoder = BrotliEncoder::new(Vec::new(), 6);
+    encoder.write_all(b"hello world").unwrap();
+    assert_decompresses("br", encoder.finish().unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_body_size_limit() {
+    let config = Config {
+        http_request_size_limit_bytes: 1024,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|req: hyper::Request<Body>| {
+        let len = if req.uri().path() == "/large" {
+            1025
+        } else {
+            1024
+        };
+        async move { hyper::Response::new(Body::from(vec![b'a'; len])) }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client
+        .send_http_request(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content.len(), 1024);
+
+    let request = tonic::Request::new(build_http_canister_request(format!(
+        "http://{}/large",
+        addr
+    )));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::OutOfRange);
+}
+
+#[tokio::test]
+async fn test_decompressed_body_size_limit() {
+    let config = Config {
+        decompress_responses: true,
+        http_request_size_limit_bytes: 1024 * 1024,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // 16 MiB of zeros compress to a few KiB.
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&vec![0u8; 16 * 1024 * 1024]).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < 64 * 1024);
+    let addr = spawn_test_server(move |_| {
+        let response = hyper::Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(compressed.clone()))
+            .unwrap();
+        async move { response }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::OutOfRange);
+}
+
+#[tokio::test]
+async fn test_per_request_decompression_override() {
+    let config = Config {
+        decompress_responses: true,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"hello world").unwrap();
+    let compressed = encoder.finish().unwrap();
+    let body = compressed.clone();
+    let addr = spawn_test_server(move |_| {
+        let response = hyper::Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(body.clone()))
+            .unwrap();
+        async move { response }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    // The global setting decompresses the body and strips the encoding header.
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client
+        .send_http_request(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, b"hello world".to_vec());
+    assert!(!response
+        .headers
+        .iter()
+        .any(|header| header.name == CONTENT_ENCODING.as_str()));
+
+    // The per-request flag returns the raw bytes with the encoding header intact.
+    let request = tonic::Request::new(CanisterHttpRequest {
+        decompress_response: Some(false),
+        ..build_http_canister_request(format!("http://{}", addr))
+    });
+    let response = client
+        .send_http_request(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, compressed);
+    assert!(response
+        .headers
+        .iter()
+        .any(|header| header.name == CONTENT_ENCODING.as_str() && header.value == b"gzip"));
+}
+
+#[tokio::test]
+async fn test_time_to_first_byte_timeout() {
+    let config = Config {
+        time_to_first_byte_timeout_secs: 1,
+        read_idle_timeout_secs: 10,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server is slow to send the response headers.
+    let addr = spawn_test_server(|_| async {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        hyper::Response::new(Body::from("hello world"))
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    assert!(status.message().contains("first byte"));
+}
+
+#[tokio::test]
+async fn test_read_idle_timeout() {
+    let config = Config {
+        time_to_first_byte_timeout_secs: 10,
+        read_idle_timeout_secs: 1,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server sends the headers and the first chunk, then stalls.
+    let addr = spawn_test_server(|_| async {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("hello".into()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            let _ = sender.send_data(" world".into()).await;
+        });
+        hyper::Response::new(body)
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    assert!(status.message().contains("response body"));
+}
+
+#[tokio::test]
+async fn test_replayed_nonce_is_rejected() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request_with_nonce = |nonce| {
+        tonic::Request::new(CanisterHttpRequest {
+            nonce: Some(nonce),
+            ..build_http_canister_request(format!("http://{}", addr))
+        })
+    };
+
+    assert!(client
+        .send_http_request(request_with_nonce(1))
+        .await
+        .is_ok());
+
+    let status = client
+        .send_http_request(request_with_nonce(1))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+    assert!(client
+        .send_http_request(request_with_nonce(2))
+        .await
+        .is_ok());
+
+    // A request that fails validation does not spend its nonce.
+    let invalid = tonic::Request::new(CanisterHttpRequest {
+        nonce: Some(3),
+        method: Some("BAD METHOD".to_string()),
+        ..build_http_canister_request(format!("http://{}", addr))
+    });
+    let status = client.send_http_request(invalid).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(client
+        .send_http_request(request_with_nonce(3))
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn test_require_https() {
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    // Without the flag, plain HTTP URLs are dispatched.
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    assert!(client.send_http_request(request).await.is_ok());
+
+    // With the flag, they are rejected.
+    let config = Config {
+        require_https: true,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_url_validation() {
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    for (url, reason) in [
+        ("/index.html".to_string(), "Url has no host"),
+        ("example.com".to_string(), "Url has no scheme"),
+        (
+            format!("ftp://{}/file", addr),
+            "Url scheme ftp is not supported",
+        ),
+    ] {
+        let request = tonic::Request::new(build_http_canister_request(url));
+        let status = client.send_http_request(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), reason);
     }
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    assert!(client.send_http_request(request).await.is_ok());
 }
 
-fn setup_grpc_server_with_https_client(
-    logger: ReplicaLogger,
-) -> CanisterHttp<HttpsConnector<HttpConnector>> {
-    let mut https = HttpsConnector::new();
-    https.https_only(true);
-    let https_client = Client::builder().build::<_, hyper::Body>(https);
-    CanisterHttp::new(https_client, logger)
+#[tokio::test]
+async fn test_max_url_length() {
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+    let config = Config {
+        max_url_length: 64,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let url = format!("http://{}/", addr);
+    let request = tonic::Request::new(build_http_canister_request(url.clone()));
+    assert!(client.send_http_request(request).await.is_ok());
+
+    let long_url = format!("{}{}", url, "a".repeat(64));
+    let request = tonic::Request::new(build_http_canister_request(long_url));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert_eq!(status.message(), "Url exceeds the limit of 64 bytes");
+}
+
+#[tokio::test]
+async fn test_allowed_methods() {
+    let config = Config {
+        allowed_methods: vec!["get".to_string()],
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(CanisterHttpRequest {
+        method: Some("GET".to_string()),
+        ..build_http_canister_request(format!("http://{}", addr))
+    });
+    assert!(client.send_http_request(request).await.is_ok());
+
+    let request = tonic::Request::new(CanisterHttpRequest {
+        method: Some("post".to_string()),
+        ..build_http_canister_request(format!("http://{}", addr))
+    });
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn test_local_address() {
+    let local_address: IpAddr = "127.0.0.2".parse().unwrap();
+    let config = Config {
+        local_address: Some(local_address),
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server answers with the address the connection originates from.
+    let make_svc = make_service_fn(|conn: &AddrStream| {
+        let remote_ip = conn.remote_addr().ip();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |_| async move {
+                Ok::<_, Infallible>(hyper::Response::new(Body::from(remote_ip.to_string())))
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&addr).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let http_client = Client::builder().build::<_, Body>(build_http_connector(&config));
+    let canister_http = CanisterHttp::new(http_client, logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client
+        .send_http_request(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, local_address.to_string().into_bytes());
+}
+
+#[tokio::test]
+async fn test_http2_prior_knowledge() {
+    let config = Config {
+        http2_prior_knowledge: true,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // An h2c server, which does not understand HTTP/1. It answers with the
+    // version of the request.
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(hyper::service::service_fn(
+            |req: hyper::Request<Body>| async move {
+                Ok::<_, Infallible>(hyper::Response::new(Body::from(format!(
+                    "{:?}",
+                    req.version()
+                ))))
+            },
+        ))
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+        .http2_only(true)
+        .serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let http_client = build_client_builder(&config).build::<_, Body>(build_http_connector(&config));
+    let canister_http = CanisterHttp::new(http_client, logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client
+        .send_http_request(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, b"HTTP/2.0");
+}
+
+#[tokio::test]
+async fn test_chunked_body_with_trailers() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // A raw TCP stub that decodes the chunked request body and forwards the
+    // request head, the body and the trailer section to the test.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        let (head, body, trailers) = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before the request was complete");
+            request.extend_from_slice(&buf[..n]);
+            if let Some(decoded) = decode_chunked_request(&request) {
+                break decoded;
+            }
+        };
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        tx.send((head, body, trailers)).unwrap();
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(CanisterHttpRequest {
+        method: Some("POST".to_string()),
+        body: b"hello world".to_vec(),
+        trailers: vec![HttpHeader {
+            name: "x-checksum".to_string(),
+            value: b"5eb63bbb".to_vec(),
+        }],
+        ..build_http_canister_request(format!("http://{}", addr))
+    });
+    client.send_http_request(request).await.unwrap();
+
+    let (head, body, trailers) = rx.await.unwrap();
+    assert!(head
+        .to_ascii_lowercase()
+        .contains("transfer-encoding: chunked"));
+    assert_eq!(body, b"hello world".to_vec());
+    assert_eq!(trailers, vec!["x-checksum: 5eb63bbb".to_string()]);
+}
+
+/// Decodes an HTTP/1.1 request with a chunked body. Returns the request head,
+/// the body and the trailer lines, or `None` if the request is incomplete.
+fn decode_chunked_request(request: &[u8]) -> Option<(String, Vec<u8>, Vec<String>)> {
+    let text = String::from_utf8_lossy(request);
+    let head_end = text.find("\r\n\r\n")?;
+    let head = text[..head_end].to_string();
+    let mut rest = &request[head_end + 4..];
+    let mut body = Vec::new();
+    loop {
+        let line_end = rest.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&rest[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.trim(), 16).ok()?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            break;
+        }
+        if rest.len() < size + 2 {
+            return None;
+        }
+        body.extend_from_slice(&rest[..size]);
+        rest = &rest[size + 2..];
+    }
+    // The trailer section ends with an empty line.
+    let trailer_section = String::from_utf8_lossy(rest);
+    let trailer_end = if trailer_section.starts_with("\r\n") {
+        0
+    } else {
+        trailer_section.find("\r\n\r\n")? + 2
+    };
+    let trailers = trailer_section[..trailer_end]
+        .split("\r\n")
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_ascii_lowercase())
+        .collect();
+    Some((head, body, trailers))
+}
+
+#[tokio::test]
+async fn test_ip_family_rejects_literal_of_other_family() {
+    let config = Config {
+        ip_family: IpFamily::V6Only,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_json_log_has_structured_request_fields() {
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let config = Config {
+        logger: ic_config::logger::Config {
+            format: LogFormat::Json,
+            target: LogTarget::File(log_file.path().to_owned()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (logger, async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let url = format!("http://{}", addr);
+    let request = tonic::Request::new(build_http_canister_request(url.clone()));
+    client.send_http_request(request).await.unwrap();
+    // Flushes the log.
+    drop(async_log_guard);
+
+    let log = std::fs::read_to_string(log_file.path()).unwrap();
+    let record = log
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|record| record["log_entry"]["canister_http"].is_object())
+        .expect("No record of the request was logged");
+    let fields = &record["log_entry"]["canister_http"];
+    assert_eq!(fields["url"], url.as_str());
+    assert_eq!(fields["status"], 200);
+    assert!(fields["latency_ms"].is_u64());
+}
+
+#[tokio::test]
+async fn test_request_id_is_logged_and_returned() {
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let config = Config {
+        logger: ic_config::logger::Config {
+            format: LogFormat::Json,
+            target: LogTarget::File(log_file.path().to_owned()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (logger, async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let mut request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    request
+        .metadata_mut()
+        .insert(REQUEST_ID_METADATA_KEY, "test-request-id".parse().unwrap());
+    let response = client.send_http_request(request).await.unwrap();
+    assert_eq!(
+        response.metadata().get(REQUEST_ID_METADATA_KEY).unwrap(),
+        "test-request-id"
+    );
+
+    // Requests without an ID get a generated one.
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client.send_http_request(request).await.unwrap();
+    let generated = response.metadata().get(REQUEST_ID_METADATA_KEY).unwrap();
+    assert!(!generated.is_empty());
+    assert_ne!(generated, "test-request-id");
+    // Flushes the log.
+    drop(async_log_guard);
+
+    let log = std::fs::read_to_string(log_file.path()).unwrap();
+    let record = log
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|record| record["log_entry"]["canister_http"]["request_id"] == "test-request-id")
+        .expect("No record of the request was logged");
+    assert_eq!(record["log_entry"]["canister_http"]["status"], 200);
+}
+
+#[tokio::test]
+async fn test_circuit_breaker() {
+    let config = Config {
+        circuit_breaker_failure_threshold: 2,
+        circuit_breaker_cooldown_secs: 1,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // Nothing listens on the address until the server is started below.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+    let request = || tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+
+    for _ in 0..2 {
+        let status = client.send_http_request(request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.message(), "Failed to connect");
+    }
+
+    // The circuit is open, so requests fail without being sent.
+    spawn_test_server_at(addr, |_| async {
+        hyper::Response::new(Body::from("hello world"))
+    });
+    let status = client.send_http_request(request()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    assert!(status.message().contains("Circuit breaker"));
+
+    // After the cooldown, requests are sent again.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(client.send_http_request(request()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_canister_rate_limit() {
+    let config = Config {
+        canister_rate_limit_per_sec: 1,
+        canister_rate_limit_burst: 2,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+    let request = |canister_id: &str| {
+        let mut request =
+            tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+        request
+            .metadata_mut()
+            .insert(CANISTER_ID_METADATA_KEY, canister_id.parse().unwrap());
+        request
+    };
+
+    // The burst is served, then the canister is throttled.
+    for _ in 0..2 {
+        assert!(client
+            .send_http_request(request("canister-a"))
+            .await
+            .is_ok());
+    }
+    let status = client
+        .send_http_request(request("canister-a"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+    // Other canisters are not affected.
+    assert!(client
+        .send_http_request(request("canister-b"))
+        .await
+        .is_ok());
+
+    // The bucket refills over time.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(client
+        .send_http_request(request("canister-a"))
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn test_max_request_bytes() {
+    let config = Config {
+        max_request_bytes: 10,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+    let request_with_body = |size| {
+        tonic::Request::new(CanisterHttpRequest {
+            method: Some("POST".to_string()),
+            body: vec![0; size],
+            ..build_http_canister_request(format!("http://{}", addr))
+        })
+    };
+
+    assert!(client
+        .send_http_request(request_with_body(10))
+        .await
+        .is_ok());
+
+    let status = client
+        .send_http_request(request_with_body(11))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_injected_headers() {
+    let config = Config {
+        injected_headers: vec![
+            ("X-Api-Token".to_string(), "secret".to_string()),
+            ("X-Origin".to_string(), "adapter".to_string()),
+        ],
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server echoes the headers of interest.
+    let addr = spawn_test_server(|req| async move {
+        let header = |name: &str| {
+            req.headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let echo = format!("{};{}", header("x-api-token"), header("x-origin"));
+        hyper::Response::new(Body::from(echo))
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let mut request = build_http_canister_request(format!("http://{}", addr));
+    request.headers.push(HttpHeader {
+        name: "X-Origin".to_string(),
+        value: b"canister".to_vec(),
+    });
+    let response = client
+        .send_http_request(tonic::Request::new(request))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, b"secret;canister");
+}
+
+#[tokio::test]
+async fn test_user_agent() {
+    let config = Config {
+        user_agent: Some("my-adapter/1.0".to_string()),
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server echoes the User-Agent.
+    let addr = spawn_test_server(|req| async move {
+        let user_agent = req.headers().get(USER_AGENT).cloned();
+        hyper::Response::new(Body::from(user_agent.unwrap().as_bytes().to_vec()))
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let mut request = build_http_canister_request(format!("http://{}", addr));
+    request.headers.clear();
+    let response = client
+        .send_http_request(tonic::Request::new(request))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, b"my-adapter/1.0");
+}
+
+/// A log drain that keeps the logged messages.
+struct CaptureDrain(Arc<Mutex<Vec<String>>>);
+
+impl slog::Drain for CaptureDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_trace_http_redacts_credentials() {
+    let config = Config {
+        trace_http: true,
+        ..Default::default()
+    };
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let logger = new_replica_logger(
+        slog::Logger::root(CaptureDrain(logs.clone()), slog::o!()),
+        &LoggerConfig {
+            level: slog::Level::Trace,
+            ..Default::default()
+        },
+    );
+
+    let addr = spawn_test_server(|_| async {
+        hyper::Response::builder()
+            .header("Set-Cookie", "session=server-secret")
+            .body(Body::from("hello world"))
+            .unwrap()
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let mut request = build_http_canister_request(format!("http://{}", addr));
+    request.headers.push(HttpHeader {
+        name: "Authorization".to_string(),
+        value: b"Bearer canister-secret".to_vec(),
+    });
+    request.headers.push(HttpHeader {
+        name: "Cookie".to_string(),
+        value: b"session=canister-secret".to_vec(),
+    });
+    client
+        .send_http_request(tonic::Request::new(request))
+        .await
+        .unwrap();
+
+    let logs = logs.lock().unwrap().join("\n");
+    assert!(logs.contains("user-agent: test"));
+    assert!(logs.contains("authorization: <redacted>"));
+    assert!(logs.contains("cookie: <redacted>"));
+    assert!(logs.contains("set-cookie: <redacted>"));
+    assert!(logs.contains("hello world"));
+    assert!(!logs.contains("secret"));
+}
+
+#[tokio::test]
+async fn test_serve_on_socket_path_with_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("adapter.socket");
+    let incoming = incoming_from_path_with_mode(&path, Some(0o600)).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let server = tokio::spawn(async move {
+        Server::builder()
+            .add_service(HttpAdapterServer::new(canister_http))
+            .serve_with_incoming(incoming)
+            .await
+    });
+
+    let socket_path = path.clone();
+    let channel = Endpoint::try_from("http://[::]:50151")
+        .unwrap()
+        .connect_with_connector(service_fn(move |_: Uri| {
+            UnixStream::connect(socket_path.clone())
+        }))
+        .await
+        .unwrap();
+    let mut client = HttpAdapterClient::new(channel);
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let response = client.send_http_request(request).await.unwrap();
+    assert_eq!(response.into_inner().content, b"hello world");
+
+    // Stopping the server removes the socket file.
+    server.abort();
+    let _ = server.await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_max_concurrent_requests() {
+    let config = Config {
+        max_concurrent_requests: 2,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        hyper::Response::new(Body::from("hello world"))
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let client = HttpAdapterClient::new(channel);
+
+    // The requests beyond the limit are rejected right away.
+    let results = futures::future::join_all((0..4).map(|_| {
+        let mut client = client.clone();
+        let request = build_http_canister_request(format!("http://{}", addr));
+        async move { client.send_http_request(tonic::Request::new(request)).await }
+    }))
+    .await;
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+    assert!(results
+        .iter()
+        .filter_map(|result| result.as_ref().err())
+        .all(|status| status.code() == tonic::Code::ResourceExhausted));
+
+    // Once the slots are free again, requests succeed.
+    let mut client = client;
+    let request = build_http_canister_request(format!("http://{}", addr));
+    assert!(client
+        .send_http_request(tonic::Request::new(request))
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn test_max_concurrent_requests_queued() {
+    let config = Config {
+        max_concurrent_requests: 1,
+        concurrency_queue_timeout_secs: 5,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        hyper::Response::new(Body::from("hello world"))
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let client = HttpAdapterClient::new(channel);
+
+    // The requests beyond the limit wait for a free slot.
+    let results = futures::future::join_all((0..3).map(|_| {
+        let mut client = client.clone();
+        let request = build_http_canister_request(format!("http://{}", addr));
+        async move { client.send_http_request(tonic::Request::new(request)).await }
+    }))
+    .await;
+    assert!(results.iter().all(|result| result.is_ok()));
+}
+
+#[tokio::test]
+async fn test_idempotency_key() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server answers with the number of requests it received.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |_| {
+        let hit = server_hits.fetch_add(1, Ordering::SeqCst) + 1;
+        async move { hyper::Response::new(Body::from(hit.to_string())) }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = |key: &str| {
+        let mut request =
+            tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+        request
+            .metadata_mut()
+            .insert(IDEMPOTENCY_KEY_METADATA_KEY, key.parse().unwrap());
+        request
+    };
+    let first = client.send_http_request(request("key-1")).await.unwrap();
+    let retry = client.send_http_request(request("key-1")).await.unwrap();
+    assert_eq!(first.into_inner(), retry.into_inner());
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    let other = client.send_http_request(request("key-2")).await.unwrap();
+    assert_eq!(other.into_inner().content, b"2");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // A key may not be reused for a different request.
+    let mut different = request("key-1");
+    different.get_mut().body = b"other".to_vec();
+    let status = client.send_http_request(different).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    // Keys are scoped to the canister.
+    let mut of_canister = request("key-1");
+    of_canister
+        .metadata_mut()
+        .insert(CANISTER_ID_METADATA_KEY, "canister-a".parse().unwrap());
+    let of_canister = client.send_http_request(of_canister).await.unwrap();
+    assert_eq!(of_canister.into_inner().content, b"3");
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_idempotency_key_concurrent_duplicates() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server answers slowly with the number of requests it received.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |_| {
+        let hit = server_hits.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            hyper::Response::new(Body::from(hit.to_string()))
+        }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let client = HttpAdapterClient::new(channel);
+
+    // Duplicates that arrive while the first request is in flight wait for
+    // its response.
+    let results = futures::future::join_all((0..3).map(|_| {
+        let mut client = client.clone();
+        let mut request =
+            tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+        request
+            .metadata_mut()
+            .insert(IDEMPOTENCY_KEY_METADATA_KEY, "key".parse().unwrap());
+        async move { client.send_http_request(request).await }
+    }))
+    .await;
+    for result in results {
+        assert_eq!(result.unwrap().into_inner().content, b"1");
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_retries_idempotent_requests() {
+    let config = Config {
+        max_retries: 2,
+        retry_backoff_millis: 10,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server fails all but every third request.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |_| {
+        let hit = server_hits.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            let status = if hit % 3 == 0 {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            hyper::Response::builder()
+                .status(status)
+                .body(Body::from("hello world"))
+                .unwrap()
+        }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = build_http_canister_request(format!("http://{}", addr));
+    let response = client
+        .send_http_request(tonic::Request::new(request.clone()))
+        .await
+        .unwrap();
+    assert_eq!(response.into_inner().status, StatusCode::OK.as_u16() as u32);
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+    // Non-idempotent requests are not retried.
+    let post = CanisterHttpRequest {
+        method: Some("POST".to_string()),
+        ..request
+    };
+    let response = client
+        .send_http_request(tonic::Request::new(post))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.into_inner().status,
+        StatusCode::SERVICE_UNAVAILABLE.as_u16() as u32
+    );
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_total_deadline_stops_retries() {
+    let config = Config {
+        max_retries: 100,
+        retry_backoff_millis: 100,
+        total_deadline_secs: Some(1),
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async {
+        hyper::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap()
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let start = std::time::Instant::now();
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    // The backoffs of 100, 200 and 400 ms fit the deadline, the next one not.
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_max_response_headers() {
+    let config = Config {
+        max_response_headers: 5,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async {
+        let mut builder = hyper::Response::builder();
+        for i in 0..10 {
+            builder = builder.header(format!("x-header-{}", i), "value");
+        }
+        builder.body(Body::from("hello world")).unwrap()
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::OutOfRange);
+}
+
+#[tokio::test]
+async fn test_max_response_header_bytes() {
+    let config = Config {
+        max_response_header_bytes: 1024,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async {
+        hyper::Response::builder()
+            .header("x-large", "a".repeat(2048))
+            .body(Body::from("hello world"))
+            .unwrap()
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    let status = client.send_http_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::OutOfRange);
+}
+
+#[tokio::test]
+async fn test_sort_response_headers() {
+    let config = Config {
+        sort_response_headers: true,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server alternates the order of the headers.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |_| {
+        let hit = server_hits.fetch_add(1, Ordering::SeqCst);
+        async move {
+            let mut names = vec!["x-b", "x-c", "x-a"];
+            if hit % 2 == 1 {
+                names.reverse();
+            }
+            let mut builder = hyper::Response::builder();
+            for name in names {
+                builder = builder.header(name, "value");
+            }
+            builder.body(Body::from("hello world")).unwrap()
+        }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        let request = tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+        responses.push(
+            client
+                .send_http_request(request)
+                .await
+                .unwrap()
+                .into_inner(),
+        );
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    // The responses differ in their date header only, if at all.
+    let names = |response: &CanisterHttpResponse| {
+        response
+            .headers
+            .iter()
+            .map(|header| header.name.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&responses[0]), names(&responses[1]));
+    let mut sorted = names(&responses[0]);
+    sorted.sort_unstable();
+    assert_eq!(names(&responses[0]), sorted);
+}
+
+struct UppercaseTransform;
+
+impl ResponseTransform for UppercaseTransform {
+    fn transform(&self, response: CanisterHttpResponse) -> CanisterHttpResponse {
+        CanisterHttpResponse {
+            content: response.content.to_ascii_uppercase(),
+            ..response
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_response_transform() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config)
+        .with_response_transform(UppercaseTransform);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let response = client
+        .send_http_request(tonic::Request::new(build_http_canister_request(format!(
+            "http://{}",
+            addr
+        ))))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.content, b"HELLO WORLD");
+}
+
+#[tokio::test]
+async fn test_tcp_listener() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    // Serve the adapter on a TCP listener, as with `--listen <addr>`.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    tokio::spawn(async move {
+        Server::builder()
+            .add_service(HttpAdapterServer::new(canister_http))
+            .serve_with_incoming(incoming_from_tcp_listener(listener))
+            .await
+            .expect("Failed to start server");
+    });
+
+    let channel = Endpoint::try_from(format!("http://{}", listen_addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = HttpAdapterClient::new(channel);
+    let response = client
+        .send_http_request(tonic::Request::new(build_http_canister_request(format!(
+            "http://{}",
+            addr
+        ))))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.content, b"hello world");
+}
+
+#[tokio::test]
+async fn test_grpc_server_with_keepalive() {
+    let config = Config {
+        http2_keepalive_interval_secs: Some(1),
+        http2_keepalive_timeout_secs: Some(1),
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let addr = spawn_test_server(|_| async { hyper::Response::new(Body::from("hello world")) });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let server = build_grpc_server(&config).add_service(HttpAdapterServer::new(canister_http));
+    tokio::spawn(async move {
+        server
+            .serve_with_incoming(incoming_from_tcp_listener(listener))
+            .await
+            .expect("Failed to start server");
+    });
+
+    let channel = Endpoint::try_from(format!("http://{}", listen_addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = HttpAdapterClient::new(channel);
+    let request = || tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    assert!(client.send_http_request(request()).await.is_ok());
+
+    // The connection survives idle periods longer than the keep-alive interval.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(client.send_http_request(request()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_health_service() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+    let adapter_service = HttpAdapterServer::new(CanisterHttp::new(Client::new(), logger, &config));
+    let health_service = health_service_for(&adapter_service).await;
+    tokio::spawn(async move {
+        build_grpc_server(&config)
+            .add_service(health_service)
+            .add_service(adapter_service)
+            .serve_with_incoming(incoming_from_tcp_listener(listener))
+            .await
+            .expect("Failed to start server");
+    });
+
+    let channel = Endpoint::try_from(format!("http://{}", listen_addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = HealthClient::new(channel);
+    for service in ["", "http_adapter.HttpAdapter"] {
+        let response = client
+            .check(HealthCheckRequest {
+                service: service.to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, ServingStatus::Serving as i32);
+    }
+}
+
+#[tokio::test]
+async fn test_cache_control_max_age() {
+    let config = Config {
+        max_cache_bytes: 1024 * 1024,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server answers with the number of requests it received.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |_| {
+        let hit = server_hits.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            hyper::Response::builder()
+                .header("Cache-Control", "public, max-age=1")
+                .body(Body::from(hit.to_string()))
+                .unwrap()
+        }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = || {
+        let mut request =
+            tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+        request
+            .metadata_mut()
+            .insert(CANISTER_ID_METADATA_KEY, "canister-a".parse().unwrap());
+        request
+    };
+    let first = client.send_http_request(request()).await.unwrap();
+    let second = client.send_http_request(request()).await.unwrap();
+    assert_eq!(first.into_inner(), second.into_inner());
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    // Once the max-age passed, the response is fetched again.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let third = client.send_http_request(request()).await.unwrap();
+    assert_eq!(third.into_inner().content, b"2");
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_cached_responses_are_not_shared() {
+    let config = Config {
+        max_cache_bytes: 1024 * 1024,
+        ..Default::default()
+    };
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    // The server answers with the number of requests it received.
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |req| {
+        let hit = server_hits.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut builder = hyper::Response::builder().header("Cache-Control", "max-age=60");
+        if req.uri().path() == "/vary" {
+            builder = builder.header("Vary", "Accept");
+        }
+        async move { builder.body(Body::from(hit.to_string())).unwrap() }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    let request = |path: &str, canister_id: &str, authorization: Option<&str>| {
+        let mut req = build_http_canister_request(format!("http://{}{}", addr, path));
+        if let Some(authorization) = authorization {
+            req.headers.push(HttpHeader {
+                name: "Authorization".to_string(),
+                value: authorization.as_bytes().to_vec(),
+            });
+        }
+        let mut request = tonic::Request::new(req);
+        request
+            .metadata_mut()
+            .insert(CANISTER_ID_METADATA_KEY, canister_id.parse().unwrap());
+        request
+    };
+    async fn content(
+        client: &mut HttpAdapterClient<Channel>,
+        request: tonic::Request<CanisterHttpRequest>,
+    ) -> Vec<u8> {
+        let response = client.send_http_request(request).await.unwrap();
+        response.into_inner().content
+    }
+
+    // Requests with credentials are always sent.
+    assert_eq!(
+        content(&mut client, request("/", "canister-a", Some("a"))).await,
+        b"1"
+    );
+    assert_eq!(
+        content(&mut client, request("/", "canister-b", Some("b"))).await,
+        b"2"
+    );
+    assert_eq!(
+        content(&mut client, request("/", "canister-a", Some("a"))).await,
+        b"3"
+    );
+
+    // Canisters do not share cached responses.
+    assert_eq!(
+        content(&mut client, request("/", "canister-a", None)).await,
+        b"4"
+    );
+    assert_eq!(
+        content(&mut client, request("/", "canister-b", None)).await,
+        b"5"
+    );
+    assert_eq!(
+        content(&mut client, request("/", "canister-a", None)).await,
+        b"4"
+    );
+    assert_eq!(hits.load(Ordering::SeqCst), 5);
+
+    // Responses that vary with the request headers are not cached.
+    assert_eq!(
+        content(&mut client, request("/vary", "canister-a", None)).await,
+        b"6"
+    );
+    assert_eq!(
+        content(&mut client, request("/vary", "canister-a", None)).await,
+        b"7"
+    );
+
+    // Responses to requests of unknown canisters are not cached.
+    let anonymous = || tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+    assert_eq!(content(&mut client, anonymous()).await, b"8");
+    assert_eq!(content(&mut client, anonymous()).await, b"9");
+}
+
+#[tokio::test]
+async fn test_response_cache_is_disabled_by_default() {
+    let config = Config::default();
+    let (logger, _async_log_guard) = new_replica_logger_from_config(&config.logger);
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let server_hits = hits.clone();
+    let addr = spawn_test_server(move |_| {
+        server_hits.fetch_add(1, Ordering::SeqCst);
+        async {
+            hyper::Response::builder()
+                .header("Cache-Control", "max-age=60")
+                .body(Body::from("hello world"))
+                .unwrap()
+        }
+    });
+
+    let canister_http = CanisterHttp::new(Client::new(), logger, &config);
+    let channel = setup_loop_channel_unix(canister_http).await;
+    let mut client = HttpAdapterClient::new(channel);
+
+    for _ in 0..2 {
+        let mut request =
+            tonic::Request::new(build_http_canister_request(format!("http://{}", addr)));
+        request
+            .metadata_mut()
+            .insert(CANISTER_ID_METADATA_KEY, "canister-a".parse().unwrap());
+        client.send_http_request(request).await.unwrap();
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+// TODO: increase functionality of this function (NET-883)
+fn build_http_canister_request(url: String) -> CanisterHttpRequest {
+    let headers = vec![HttpHeader {
+        name: "User-Agent".to_string(),
+        value: "test".as_bytes().to_vec(),
+    }];
+
+    CanisterHttpRequest {
+        url,
+        body: "".to_string().into_bytes(),
+        headers,
+        decompress_response: None,
+        nonce: None,
+        method: None,
+        trailers: vec![],
+        return_peer_cert: false,
+    }
+}
+
+fn setup_grpc_server_with_https_client(
+    logger: ReplicaLogger,
+    config: &Config,
+) -> CanisterHttp<
+    PeerCertificateConnector<
+        TlsHandshakeConnector<TimeoutConnector<HttpConnector<FamilyResolver>>>,
+    >,
+> {
+    let https_client = build_https_client(config);
+    CanisterHttp::new(https_client, logger, config)
+}
+
+/// Spawns a plain HTTP server on localhost that answers every request with
+/// the response built by `make_response`. Returns the address of the server.
+fn spawn_test_server<F, R>(make_response: F) -> SocketAddr
+where
+    F: Fn(hyper::Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = hyper::Response<Body>> + Send + 'static,
+{
+    spawn_test_server_at(([127, 0, 0, 1], 0).into(), make_response)
+}
+
+/// Like `spawn_test_server`, but binds the server to the given address.
+fn spawn_test_server_at<F, R>(addr: SocketAddr, make_response: F) -> SocketAddr
+where
+    F: Fn(hyper::Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = hyper::Response<Body>> + Send + 'static,
+{
+    let make_svc = make_service_fn(move |_| {
+        let make_response = make_response.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                let response = make_response(req);
+                async move { Ok::<_, Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&addr).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
 }
 
 async fn setup_loop_channel_unix<C: Clone + Connect + Send + Sync + 'static>(
@@ -568,7 +568,9 @@ mod tests {
         let state_modifications = result.exec_output.state.unwrap();
         assert_eq!(WasmResult::Reply([].to_vec()), wasm_result);
 
-        wasm_memory.deserialize_delta(state_modifications.wasm_memory.page_delta);
+        wasm_memory
+            .deserialize_delta(state_modifications.wasm_memory.page_delta)
+            .unwrap();
         assert_eq!(
             vec![1, 2, 3, 4],
             wasm_memory.get_page(PageIndex::new(0))[16..20].to_vec()
@@ -821,7 +823,9 @@ mod tests {
         let state_modifications = result.exec_output.state.unwrap();
         assert_eq!(WasmResult::Reply([].to_vec()), wasm_result);
 
-        stable_memory.deserialize_delta(state_modifications.stable_memory.page_delta);
+        stable_memory
+            .deserialize_delta(state_modifications.stable_memory.page_delta)
+            .unwrap();
         assert_eq!(
             vec![1, 2, 3, 4],
             stable_memory.get_page(PageIndex::new(0))[16..20].to_vec()
@@ -951,7 +955,9 @@ mod tests {
         let state_modifications = result.exec_output.state.unwrap();
         assert_eq!(WasmResult::Reply([].to_vec()), wasm_result);
 
-        wasm_memory.deserialize_delta(state_modifications.wasm_memory.page_delta);
+        wasm_memory
+            .deserialize_delta(state_modifications.wasm_memory.page_delta)
+            .unwrap();
         assert_eq!(
             vec![1, 2, 3, 4],
             wasm_memory.get_page(PageIndex::new(0))[16..20].to_vec()
@@ -982,7 +988,9 @@ mod tests {
         let state_modifications = result.exec_output.state.unwrap();
         assert_eq!(WasmResult::Reply([].to_vec()), wasm_result);
 
-        wasm_memory.deserialize_delta(state_modifications.wasm_memory.page_delta);
+        wasm_memory
+            .deserialize_delta(state_modifications.wasm_memory.page_delta)
+            .unwrap();
         assert_eq!(
             vec![5, 6, 7, 8],
             wasm_memory.get_page(PageIndex::new(0))[32..36].to_vec()
@@ -1051,7 +1059,9 @@ mod tests {
         let state_modifications = result.exec_output.state.unwrap();
         assert_eq!(WasmResult::Reply([].to_vec()), wasm_result);
 
-        stable_memory.deserialize_delta(state_modifications.stable_memory.page_delta);
+        stable_memory
+            .deserialize_delta(state_modifications.stable_memory.page_delta)
+            .unwrap();
         assert_eq!(
             vec![1, 2, 3, 4],
             stable_memory.get_page(PageIndex::new(0))[16..20].to_vec()
@@ -1082,7 +1092,9 @@ mod tests {
         let state_modifications = result.exec_output.state.unwrap();
         assert_eq!(WasmResult::Reply([].to_vec()), wasm_result);
 
-        stable_memory.deserialize_delta(state_modifications.stable_memory.page_delta);
+        stable_memory
+            .deserialize_delta(state_modifications.stable_memory.page_delta)
+            .unwrap();
         assert_eq!(
             vec![5, 6, 7, 8],
             stable_memory.get_page(PageIndex::new(0))[32..36].to_vec()
@@ -646,7 +646,8 @@ impl SandboxedExecutionController {
                 execution_state
                     .wasm_memory
                     .page_map
-                    .deserialize_delta(state_modifications.wasm_memory.page_delta);
+                    .deserialize_delta(state_modifications.wasm_memory.page_delta)
+                    .expect("The sandbox process sent a malformed page-delta");
                 execution_state.wasm_memory.size = state_modifications.wasm_memory.size;
                 execution_state.wasm_memory.sandbox_memory = SandboxMemory::synced(
                     wrap_remote_memory(&sandbox_process, next_wasm_memory_id),
@@ -655,7 +656,8 @@ impl SandboxedExecutionController {
                 execution_state
                     .stable_memory
                     .page_map
-                    .deserialize_delta(state_modifications.stable_memory.page_delta);
+                    .deserialize_delta(state_modifications.stable_memory.page_delta)
+                    .expect("The sandbox process sent a malformed page-delta");
                 execution_state.stable_memory.size = state_modifications.stable_memory.size;
                 execution_state.stable_memory.sandbox_memory = SandboxMemory::synced(
                     wrap_remote_memory(&sandbox_process, next_stable_memory_id),
@@ -728,7 +730,8 @@ impl SandboxedExecutionController {
         let mut wasm_memory = Memory::new(wasm_page_map, reply.wasm_memory_modifications.size);
         wasm_memory
             .page_map
-            .deserialize_delta(reply.wasm_memory_modifications.page_delta);
+            .deserialize_delta(reply.wasm_memory_modifications.page_delta)
+            .expect("The sandbox process sent a malformed page-delta");
         wasm_memory.sandbox_memory =
             SandboxMemory::synced(wrap_remote_memory(&sandbox_process, next_wasm_memory_id));
 
@@ -97,25 +97,7 @@ pub fn verify_certificate(
         canister: BTreeMap<CanisterId, CanisterView>,
     }
 
-    let certificate: Certificate = parse_certificate(certificate)?;
-
-    let key = if let Some(delegation) = &certificate.delegation {
-        let subnet_id = PrincipalId::try_from(&*delegation.subnet_id)
-            .map(SubnetId::from)
-            .map_err(|err| {
-                CertificateValidationError::DeserError(format!(
-                    "failed to parse delegation subnet id: {}",
-                    err
-                ))
-            })?;
-        verify_delegation_certificate(&delegation.certificate, &subnet_id, root_pk, canister_id)?
-    } else {
-        *root_pk
-    };
-
-    verify_certificate_signature(&certificate, &key)?;
-
-    let replica_labeled_tree = parse_tree(certificate.tree)?;
+    let replica_labeled_tree = verify_certified_tree(certificate, canister_id, root_pk)?;
     let replica_state = ReplicaState::deserialize(LabeledTreeDeserializer::new(
         &replica_labeled_tree,
     ))
@@ -147,6 +129,41 @@ pub fn verify_certificate(
     Ok(Time::from_nanos_since_unix_epoch(replica_state.time.0))
 }
 
+/// Verifies the signature of the specified certificate, using the key of its
+/// subnet delegation if present, and returns the certified tree.
+///
+/// A subnet delegation must cover `effective_canister_id`.
+pub fn verify_certified_tree(
+    certificate: &[u8],
+    effective_canister_id: &CanisterId,
+    root_pk: &ThresholdSigPublicKey,
+) -> Result<LabeledTree<Vec<u8>>, CertificateValidationError> {
+    let certificate: Certificate = parse_certificate(certificate)?;
+
+    let key = if let Some(delegation) = &certificate.delegation {
+        let subnet_id = PrincipalId::try_from(&*delegation.subnet_id)
+            .map(SubnetId::from)
+            .map_err(|err| {
+                CertificateValidationError::DeserError(format!(
+                    "failed to parse delegation subnet id: {}",
+                    err
+                ))
+            })?;
+        verify_delegation_certificate(
+            &delegation.certificate,
+            &subnet_id,
+            root_pk,
+            effective_canister_id,
+        )?
+    } else {
+        *root_pk
+    };
+
+    verify_certificate_signature(&certificate, &key)?;
+
+    parse_tree(certificate.tree)
+}
+
 fn verify_delegation_certificate(
     certificate: &[u8],
     subnet_id: &SubnetId,
@@ -11,7 +11,7 @@ use ic_crypto_tree_hash::{flatmap, Digest, Label, LabeledTree};
 use ic_crypto_utils_threshold_sig::parse_threshold_sig_key_from_der;
 use ic_types::Time;
 
-use crate::{verify_certificate, CanisterId, CertificateValidationError};
+use crate::{verify_certificate, verify_certified_tree, CanisterId, CertificateValidationError};
 
 #[test]
 fn should_validate_subnet_delegation_test_vector() {
@@ -435,6 +435,39 @@ fn should_fail_on_unexpected_tree() {
     ));
 }
 
+#[test]
+fn should_return_certified_tree() {
+    let tree = LabeledTree::SubTree(flatmap![
+        Label::from("time") => LabeledTree::Leaf(b"some_data".to_vec())
+    ]);
+    let (_cert, pk, cbor) = CertificateBuilder::new(CustomTree(tree.clone()))
+        .with_delegation(CertificateBuilder::new(SubnetData {
+            subnet_id: subnet_id(1),
+            canister_id_ranges: vec![(canister_id(0), canister_id(10))],
+        }))
+        .build();
+
+    let verification_result = verify_certified_tree(&cbor, &canister_id(1), &pk);
+
+    assert_eq!(verification_result.expect("expect valid signature"), tree);
+}
+
+#[test]
+fn should_fail_certified_tree_with_invalid_signature() {
+    let (_cert, pk, cbor) = CertificateBuilder::new(CustomTree(LabeledTree::SubTree(flatmap![
+        Label::from("time") => LabeledTree::Leaf(b"some_data".to_vec())
+    ])))
+    .with_invalid_sig()
+    .build();
+
+    let verification_result = verify_certified_tree(&cbor, &canister_id(1), &pk);
+
+    assert!(matches!(
+        verification_result,
+        Err(CertificateValidationError::InvalidSignature { .. })
+    ));
+}
+
 fn random_certified_data() -> Digest {
     let mut random_certified_data: [u8; 32] = [0; 32];
     thread_rng().fill(&mut random_certified_data);
@@ -21,7 +21,10 @@ pub mod handle;
 pub mod process_pool;
 use crate::mio::InputSource;
 use crossbeam_channel::unbounded;
-pub use handle::{FarmInfo, IcControl, IcEndpoint, IcHandle, IcSubnet, RuntimeDescriptor};
+pub use handle::{
+    EndpointClient, FarmInfo, IcControl, IcEndpoint, IcHandle, IcSubnet, MetricsError,
+    NodeResources, RuntimeDescriptor, SeededIcHandle, SshError, StatusError,
+};
 pub use inner::*;
 use std::collections::BTreeSet;
 use std::fs::{create_dir_all, File};
@@ -228,6 +231,7 @@ impl IcManager {
                 started_at: Instant::now(),
                 ssh_key_pairs: vec![],
                 node_id: nc.node_id,
+                client: Default::default(),
             }
         };
 
@@ -20,6 +20,27 @@ const DEFAULT_REQ_TIMEOUT: Duration = Duration::from_secs(300);
 const LINEAR_BACKOFF_RETRY_DELAY: Duration = Duration::from_millis(1500);
 const MAX_NUMBER_OF_RETRIES: usize = 3;
 
+/// Timeouts and retries of the requests to Farm.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FarmConfig {
+    /// The time a request to Farm may take, including all retries.
+    pub timeout: Duration,
+    /// The number of attempts to send a request before giving up.
+    pub max_retries: usize,
+    /// The delay between two attempts.
+    pub retry_delay: Duration,
+}
+
+impl Default for FarmConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_REQ_TIMEOUT,
+            max_retries: MAX_NUMBER_OF_RETRIES,
+            retry_delay: LINEAR_BACKOFF_RETRY_DELAY,
+        }
+    }
+}
+
 /// Farm managed resources that make up the Internet Computer under test. The
 /// `Farm`-structure translates abstract requests (for resources) to concrete
 /// http-requests.
@@ -28,18 +49,24 @@ pub struct Farm {
     pub base_url: Url,
     pub logger: Logger,
     client: Client,
+    config: FarmConfig,
 }
 
 impl Farm {
     pub fn new(base_url: Url, logger: Logger) -> Self {
+        Self::new_with_config(base_url, logger, FarmConfig::default())
+    }
+
+    pub fn new_with_config(base_url: Url, logger: Logger, config: FarmConfig) -> Self {
         let client = reqwest::blocking::ClientBuilder::new()
-            .timeout(DEFAULT_REQ_TIMEOUT)
+            .timeout(config.timeout)
             .build()
             .expect("This should not fail.");
         Farm {
             base_url,
             client,
             logger,
+            config,
         }
     }
 
@@ -170,9 +197,9 @@ impl Farm {
 
     fn retry_until_success(&self, rb: RequestBuilder) -> FarmResult<reqwest::blocking::Response> {
         let started_at = Instant::now();
-        for _ in 0..MAX_NUMBER_OF_RETRIES {
+        for _ in 0..self.config.max_retries {
             let mut req = rb.try_clone().expect("could not clone a request builder");
-            if let Some(t) = DEFAULT_REQ_TIMEOUT.checked_sub(started_at.elapsed()) {
+            if let Some(t) = self.config.timeout.checked_sub(started_at.elapsed()) {
                 req = req.timeout(t);
             } else {
                 break;
@@ -192,7 +219,7 @@ impl Farm {
                     }
                 }
             }
-            std::thread::sleep(LINEAR_BACKOFF_RETRY_DELAY);
+            std::thread::sleep(self.config.retry_delay);
         }
         Err(FarmError::TooManyRetries {
             message: String::from(
@@ -121,7 +121,7 @@ use crate::ic_manager::handle::READY_RESPONSE_TIMEOUT;
 use crate::ic_manager::{FarmInfo, IcEndpoint, IcHandle, IcSubnet, RuntimeDescriptor};
 use crate::prod_tests::cli::AuthorizedSshAccount;
 use crate::prod_tests::driver_setup::{AUTHORIZED_SSH_ACCOUNTS, FARM_BASE_URL, FARM_GROUP_NAME};
-use crate::prod_tests::farm::Farm;
+use crate::prod_tests::farm::{Farm, FarmConfig};
 use crate::prod_tests::test_env::{HasIcPrepDir, TestEnv};
 use anyhow::{bail, Result};
 use ic_agent::{agent::http_transport::ReqwestHttpReplicaV2Transport, Agent, AgentError, Identity};
@@ -188,8 +188,10 @@ impl IcHandleConstructor for TestEnv {
                     group_name: group_name.clone(),
                     vm_name: n.node_id.to_string(),
                     url: farm_url.clone(),
+                    config: FarmConfig::default(),
                 }),
                 is_root_subnet: s.map_or(false, |s| s.subnet_id == root_subnet_id),
+                client: Default::default(),
             });
         }
 
@@ -1,6 +1,7 @@
 use crate::ic_manager::{FarmInfo, IcEndpoint, IcHandle, IcSubnet, RuntimeDescriptor};
 use crate::prod_tests::cli::AuthorizedSshAccount;
 use crate::prod_tests::driver_setup::{AUTHORIZED_SSH_ACCOUNTS, FARM_BASE_URL, FARM_GROUP_NAME};
+use crate::prod_tests::farm::FarmConfig;
 use crate::prod_tests::test_env::{HasIcPrepDir, TestEnv};
 use crate::prod_tests::test_env_api::*;
 use anyhow::{bail, Result};
@@ -46,8 +47,10 @@ impl IcHandleConstructor for TestEnv {
                     group_name: group_name.clone(),
                     vm_name: n.node_id.to_string(),
                     url: farm_url.clone(),
+                    config: FarmConfig::default(),
                 }),
                 is_root_subnet: s.map_or(false, |s| Some(s.subnet_id) == root_subnet_id),
+                client: Default::default(),
             });
         }
 
@@ -1,3 +1,7 @@
 fn main() {
-    build_info_build::build_script();
+    // The build timestamp in the build metadata is taken from SOURCE_DATE_EPOCH
+    // when it is set, so the metadata must be regenerated when it changes.
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // The versions of the dependencies are exposed by get_dependency_versions.
+    build_info_build::build_script().collect_dependencies(true);
 }
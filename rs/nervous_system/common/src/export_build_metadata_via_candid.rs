@@ -1,3 +1,4 @@
+use candid::{CandidType, Deserialize};
 use dfn_candid::candid_one;
 use dfn_core::over;
 
@@ -15,6 +16,12 @@ use dfn_core::over;
 ///
 ///   expose_build_metadata! {}
 ///
+/// To only report some of the enabled features, pass a `FeatureFilter`:
+///
+///   expose_build_metadata! {
+///       features: ic_nervous_system_common::export_build_metadata_via_candid::FeatureFilter::Prefix("test_")
+///   }
+///
 /// Notice the lack of semicolon. This is allowed when (curly) braces are used
 /// instead of paren.
 ///
@@ -41,10 +48,150 @@ macro_rules! expose_build_metadata {
         }
 
         #[candid::candid_method(query, rename = "get_build_metadata")]
-        fn get_build_metadata_() -> &'static str {
+        fn get_build_metadata_() -> String {
             export_build_metadata_via_candid::get_build_metadata_()
         }
     };
+    (features: $filter:expr) => {
+        use ic_nervous_system_common::export_build_metadata_via_candid;
+
+        #[export_name = "canister_query get_build_metadata"]
+        fn get_build_metadata() {
+            export_build_metadata_via_candid::get_build_metadata_filtered($filter)
+        }
+
+        #[candid::candid_method(query, rename = "get_build_metadata")]
+        fn get_build_metadata_() -> String {
+            export_build_metadata_via_candid::get_build_metadata_filtered_(&$filter)
+        }
+    };
+}
+
+/// Creates a candid method named get_build_metadata_json, which returns the
+/// same information as get_build_metadata, but as a JSON object. This is
+/// easier to consume programmatically than the key/value text.
+///
+/// Usage is the same as for `expose_build_metadata!`:
+///
+///   expose_build_metadata_json! {}
+#[macro_export]
+macro_rules! expose_build_metadata_json {
+    () => {
+        #[export_name = "canister_query get_build_metadata_json"]
+        fn get_build_metadata_json() {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_build_metadata_json()
+        }
+
+        #[candid::candid_method(query, rename = "get_build_metadata_json")]
+        fn get_build_metadata_json_() -> String {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_build_metadata_json_()
+        }
+    };
+}
+
+/// Creates a candid method named get_build_metadata_struct, which returns the
+/// same information as get_build_metadata_json, but as a candid record of type
+/// `BuildMetadata`. This gives dfx and agents a typed response instead of text
+/// that needs to be parsed.
+///
+/// Usage is the same as for `expose_build_metadata!`:
+///
+///   expose_build_metadata_struct! {}
+#[macro_export]
+macro_rules! expose_build_metadata_struct {
+    () => {
+        #[export_name = "canister_query get_build_metadata_struct"]
+        fn get_build_metadata_struct() {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_build_metadata_struct()
+        }
+
+        #[candid::candid_method(query, rename = "get_build_metadata_struct")]
+        fn get_build_metadata_struct_(
+        ) -> ic_nervous_system_common::export_build_metadata_via_candid::BuildMetadata {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_build_metadata_struct_()
+        }
+    };
+}
+
+/// Creates a candid method named get_build_metadata_field, which takes the name
+/// of a single field of get_build_metadata (e.g. "crate_name") and returns its
+/// value, or null if there is no such field.
+///
+/// Usage is the same as for `expose_build_metadata!`:
+///
+///   expose_build_metadata_field! {}
+#[macro_export]
+macro_rules! expose_build_metadata_field {
+    () => {
+        #[export_name = "canister_query get_build_metadata_field"]
+        fn get_build_metadata_field() {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_build_metadata_field()
+        }
+
+        #[candid::candid_method(query, rename = "get_build_metadata_field")]
+        fn get_build_metadata_field_(name: String) -> Option<String> {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_build_metadata_field_(
+                name,
+            )
+        }
+    };
+}
+
+/// Creates a candid method named get_dependency_versions, which returns the
+/// versions of the direct dependencies captured at build time, one
+/// `name: version` line per dependency. This allows to confirm which versions
+/// of, e.g., candid or dfn_core a deployed canister embeds.
+///
+/// Usage is the same as for `expose_build_metadata!`:
+///
+///   expose_dependency_versions! {}
+#[macro_export]
+macro_rules! expose_dependency_versions {
+    () => {
+        #[export_name = "canister_query get_dependency_versions"]
+        fn get_dependency_versions() {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_dependency_versions()
+        }
+
+        #[candid::candid_method(query, rename = "get_dependency_versions")]
+        fn get_dependency_versions_() -> String {
+            ic_nervous_system_common::export_build_metadata_via_candid::get_dependency_versions_()
+        }
+    };
+}
+
+build_info::build_info!(fn build_info);
+
+/// Selects the enabled features that `expose_build_metadata!` reports.
+#[derive(Clone, Debug)]
+pub enum FeatureFilter {
+    /// Only the features whose name starts with the given prefix.
+    Prefix(&'static str),
+    /// Only the given features.
+    Include(&'static [&'static str]),
+    /// All features except the given ones.
+    Exclude(&'static [&'static str]),
+}
+
+impl FeatureFilter {
+    /// Returns true if `feature` is reported.
+    pub fn matches(&self, feature: &str) -> bool {
+        match self {
+            FeatureFilter::Prefix(prefix) => feature.starts_with(prefix),
+            FeatureFilter::Include(features) => features.contains(&feature),
+            FeatureFilter::Exclude(features) => !features.contains(&feature),
+        }
+    }
+}
+
+/// Describes how the binary was built. Returned by get_build_metadata_struct.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BuildMetadata {
+    pub profile: String,
+    pub optimization_level: String,
+    pub crate_name: String,
+    pub enabled_features: Vec<String>,
+    pub compiler_version: String,
 }
 
 /// Returns a string that describes how the binary was built.
@@ -52,21 +199,150 @@ pub fn get_build_metadata() {
     over(candid_one, |()| get_build_metadata_())
 }
 
-pub fn get_build_metadata_() -> &'static str {
-    build_info::format!(
+pub fn get_build_metadata_() -> String {
+    let mut build_metadata = build_info::format!(
         "\
           profile: {}\n\
           optimization_level: {}\n\
           crate_name: {}\n\
           enabled_features: {}\n\
           compiler_version: {}\n\
+          timestamp: {}\n\
+          target: {}\n\
         ",
         $.profile,
         $.optimization_level,
         $.crate_info.name,
         $.crate_info.enabled_features,
-        $.compiler.version
+        $.compiler.version,
+        // build_info takes the timestamp from SOURCE_DATE_EPOCH if it is set,
+        // which keeps the output deterministic under reproducible builds.
+        $.timestamp,
+        $.target.triple
     )
+    .to_string();
+
+    let (git_commit, git_dirty) = git_revision();
+    build_metadata.push_str(&format!(
+        "\
+          git_commit: {}\n\
+          git_dirty: {}\n\
+        ",
+        git_commit, git_dirty
+    ));
+
+    build_metadata
+}
+
+/// Returns the commit the binary was built from and whether the checkout was
+/// dirty, or "unknown" for both if it was not built from a git checkout.
+fn git_revision() -> (String, String) {
+    // The source revision is only known if the crate was built from a git
+    // checkout, so it is looked up at run time rather than failing the build.
+    match build_info()
+        .version_control
+        .as_ref()
+        .and_then(|version_control| version_control.git())
+    {
+        Some(git) => (git.commit_id.clone(), git.dirty.to_string()),
+        None => ("unknown".to_string(), "unknown".to_string()),
+    }
+}
+
+/// Same as `get_build_metadata`, but only reports the enabled features that
+/// match `filter`.
+pub fn get_build_metadata_filtered(filter: FeatureFilter) {
+    over(candid_one, |()| get_build_metadata_filtered_(&filter))
+}
+
+/// The filtered features are reported as a comma-separated list.
+pub fn get_build_metadata_filtered_(filter: &FeatureFilter) -> String {
+    let enabled_features: Vec<&str> = build_info()
+        .crate_info
+        .enabled_features
+        .iter()
+        .map(String::as_str)
+        .filter(|feature| filter.matches(feature))
+        .collect();
+    get_build_metadata_()
+        .lines()
+        .map(|line| {
+            if line.starts_with("enabled_features: ") {
+                format!("enabled_features: {}\n", enabled_features.join(", "))
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect()
+}
+
+/// Returns the value of a single field of the build metadata.
+pub fn get_build_metadata_field() {
+    over(candid_one, get_build_metadata_field_)
+}
+
+pub fn get_build_metadata_field_(name: String) -> Option<String> {
+    let build_info = build_info();
+    let value = match name.as_str() {
+        "profile" => build_info.profile.clone(),
+        "optimization_level" => build_info.optimization_level.to_string(),
+        "crate_name" => build_info.crate_info.name.clone(),
+        "enabled_features" => build_info.crate_info.enabled_features.join(", "),
+        "compiler_version" => build_info.compiler.version.to_string(),
+        "timestamp" => build_info.timestamp.to_string(),
+        "target" => build_info.target.triple.clone(),
+        "git_commit" => git_revision().0,
+        "git_dirty" => git_revision().1,
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Returns the versions of the direct dependencies of the binary.
+pub fn get_dependency_versions() {
+    over(candid_one, |()| get_dependency_versions_())
+}
+
+pub fn get_dependency_versions_() -> String {
+    build_info()
+        .crate_info
+        .dependencies
+        .iter()
+        .map(|dependency| format!("{}: {}\n", dependency.name, dependency.version))
+        .collect()
+}
+
+/// Returns a candid record that describes how the binary was built.
+pub fn get_build_metadata_struct() {
+    over(candid_one, |()| get_build_metadata_struct_())
+}
+
+pub fn get_build_metadata_struct_() -> BuildMetadata {
+    let build_info = build_info();
+    BuildMetadata {
+        profile: build_info.profile.clone(),
+        optimization_level: build_info.optimization_level.to_string(),
+        crate_name: build_info.crate_info.name.clone(),
+        enabled_features: build_info.crate_info.enabled_features.clone(),
+        compiler_version: build_info.compiler.version.to_string(),
+    }
+}
+
+/// Returns a JSON object that describes how the binary was built.
+pub fn get_build_metadata_json() {
+    over(candid_one, |()| get_build_metadata_json_())
+}
+
+pub fn get_build_metadata_json_() -> String {
+    let build_info = build_info();
+    serde_json::json!({
+        "profile": build_info.profile,
+        "optimization_level": build_info.optimization_level.to_string(),
+        "crate_name": build_info.crate_info.name,
+        "enabled_features": build_info.crate_info.enabled_features,
+        "compiler_version": build_info.compiler.version.to_string(),
+    })
+    .to_string()
 }
 
 #[test]
@@ -79,6 +355,10 @@ fn test_get_build_metadata() {
         "crate_name: ",
         "enabled_features: ",
         "compiler_version: ",
+        "timestamp: ",
+        "target: ",
+        "git_commit: ",
+        "git_dirty: ",
     ] {
         assert!(
             build_metadata.contains(chunk),
@@ -91,3 +371,124 @@ fn test_get_build_metadata() {
         );
     }
 }
+
+#[test]
+fn test_get_build_metadata_filtered() {
+    let build_metadata = get_build_metadata_();
+    let filtered = get_build_metadata_filtered_(&FeatureFilter::Include(&[]));
+
+    // Only the list of enabled features changes.
+    assert_eq!(build_metadata.lines().count(), filtered.lines().count());
+    for (line, filtered_line) in build_metadata.lines().zip(filtered.lines()) {
+        if line.starts_with("enabled_features: ") {
+            assert_eq!(filtered_line, "enabled_features: ");
+        } else {
+            assert_eq!(line, filtered_line);
+        }
+    }
+}
+
+#[test]
+fn test_feature_filter() {
+    let prefix = FeatureFilter::Prefix("test_");
+    assert!(prefix.matches("test_feature"));
+    assert!(!prefix.matches("internal"));
+
+    let include = FeatureFilter::Include(&["a", "b"]);
+    assert!(include.matches("a"));
+    assert!(!include.matches("c"));
+
+    let exclude = FeatureFilter::Exclude(&["a"]);
+    assert!(!exclude.matches("a"));
+    assert!(exclude.matches("c"));
+}
+
+#[test]
+fn test_get_build_metadata_json() {
+    let build_metadata: serde_json::Value =
+        serde_json::from_str(&get_build_metadata_json_()).unwrap();
+
+    for field in [
+        "profile",
+        "optimization_level",
+        "crate_name",
+        "compiler_version",
+    ] {
+        let value = build_metadata[field].as_str().unwrap_or_else(|| {
+            panic!(
+                "field {} is missing in build_metadata: {}",
+                field, build_metadata
+            )
+        });
+        assert!(!value.is_empty(), "field {} is empty", field);
+    }
+    // The list of enabled features may legitimately be empty.
+    assert!(build_metadata["enabled_features"].is_array());
+}
+
+#[test]
+fn test_get_build_metadata_struct() {
+    let encoded = candid::Encode!(&get_build_metadata_struct_()).unwrap();
+    let build_metadata = candid::Decode!(&encoded, BuildMetadata).unwrap();
+
+    for (field, value) in [
+        ("profile", &build_metadata.profile),
+        ("optimization_level", &build_metadata.optimization_level),
+        ("crate_name", &build_metadata.crate_name),
+        ("compiler_version", &build_metadata.compiler_version),
+    ] {
+        assert_eq!(
+            get_build_metadata_field_(field.to_string()).as_ref(),
+            Some(value),
+            "field {} differs from get_build_metadata",
+            field
+        );
+    }
+}
+
+#[test]
+fn test_get_build_metadata_field() {
+    let build_metadata = get_build_metadata_();
+
+    for field in [
+        "profile",
+        "crate_name",
+        "compiler_version",
+        "target",
+        "git_commit",
+        "git_dirty",
+    ] {
+        let value = get_build_metadata_field_(field.to_string())
+            .unwrap_or_else(|| panic!("field {} not found", field));
+        assert!(
+            build_metadata.contains(&format!("{}: {}\n", field, value)),
+            "\
+              field: {}\n\
+              value: {}\n\
+              build_metadata: {}\
+            ",
+            field,
+            value,
+            build_metadata,
+        );
+    }
+}
+
+#[test]
+fn test_get_dependency_versions() {
+    let dependency_versions = get_dependency_versions_();
+
+    assert!(
+        dependency_versions
+            .lines()
+            .any(|line| line.starts_with("candid: ")),
+        "dependency_versions: {}",
+        dependency_versions,
+    );
+}
+
+#[test]
+fn test_get_build_metadata_unknown_field() {
+    assert_eq!(get_build_metadata_field_("unknown".to_string()), None);
+    assert_eq!(get_build_metadata_field_("".to_string()), None);
+}
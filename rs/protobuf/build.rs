@@ -181,6 +181,17 @@ fn build_log_proto() {
         malicious_behaviour
     );
 
+    add_log_proto_derives!(
+        config,
+        CanisterHttpLogEntry,
+        "log.canister_http_log_entry.v1",
+        canister_http,
+        url,
+        status,
+        latency_ms,
+        request_id
+    );
+
     compile_protos(config, &["def/log/log_entry/v1/log_entry.proto"]);
 }
 
@@ -18,6 +18,12 @@ import_mod!(
     v1,
     "malicious_behaviour_log_entry.v1"
 );
+import_mod!(
+    "log",
+    canister_http_log_entry,
+    v1,
+    "canister_http_log_entry.v1"
+);
 
 pub mod log_entry {
     pub mod v1 {
@@ -47,6 +53,7 @@ pub mod log_entry {
                 crate::serialize_fallback_for!(self, ser, ingress_message);
                 crate::serialize_fallback_for!(self, ser, block);
                 crate::serialize_fallback_for!(self, ser, malicious_behaviour);
+                crate::serialize_fallback_for!(self, ser, canister_http);
                 Ok(())
             }
 
@@ -8,10 +8,16 @@ use ic_sys::PageBytes;
 pub use ic_sys::{PageIndex, PAGE_SIZE};
 use ic_utils::deterministic_operations::deterministic_copy_from_slice;
 pub use page_allocator::{
-    allocated_pages_count, PageAllocatorSerialization, PageDeltaSerialization, PageSerialization,
+    allocated_pages_count, diff_page_deltas, PageAllocatorSerialization, PageBytesSerialization,
+    PageDeltaSerialization, PageSerialization,
 };
 // Exported publicly for benchmarking.
-pub use page_allocator::{DefaultPageAllocatorImpl, HeapBasedPageAllocator, PageAllocatorInner};
+#[cfg(feature = "access_stats")]
+pub use page_allocator::PageAccessStats;
+pub use page_allocator::{
+    AllocatorSnapshot, DefaultPageAllocatorImpl, HeapBasedPageAllocator, PageAllocatorInner,
+    PageDeltaError, PageWriteError,
+};
 // NOTE: We use a persistent map to make snapshotting of a PageMap a cheap
 // operation. This allows us to simplify canister state management: we can
 // simply have a copy of the whole PageMap in every canister snapshot.
@@ -161,6 +167,14 @@ pub enum PersistenceError {
     },
     /// (Slice) size is not equal to page size.
     BadPageSize { expected: usize, actual: usize },
+    /// Serialized page-delta is malformed.
+    InvalidPageDelta(PageDeltaError),
+}
+
+impl From<PageDeltaError> for PersistenceError {
+    fn from(err: PageDeltaError) -> Self {
+        PersistenceError::InvalidPageDelta(err)
+    }
 }
 
 impl PersistenceError {
@@ -206,6 +220,7 @@ impl std::fmt::Display for PersistenceError {
                 "Bad slice size: expected {}, actual {}",
                 expected, actual
             ),
+            PersistenceError::InvalidPageDelta(err) => write!(f, "Invalid page-delta: {}", err),
         }
     }
 }
@@ -317,9 +332,9 @@ impl PageMap {
         let checkpoint = Checkpoint::deserialize(page_map.checkpoint)?;
         let page_allocator = PageAllocator::deserialize(page_map.page_allocator);
         let page_delta =
-            PageDelta::from(page_allocator.deserialize_page_delta(page_map.page_delta));
+            PageDelta::from(page_allocator.deserialize_page_delta(page_map.page_delta)?);
         let round_delta =
-            PageDelta::from(page_allocator.deserialize_page_delta(page_map.round_delta));
+            PageDelta::from(page_allocator.deserialize_page_delta(page_map.round_delta)?);
         Ok(Self {
             checkpoint,
             base_height: page_map.base_height,
@@ -350,10 +365,15 @@ impl PageMap {
     }
 
     /// Creates and applies the page delta from the given serialization-friendly
-    /// representation.
-    pub fn deserialize_delta(&mut self, page_delta: PageDeltaSerialization) {
-        let page_delta = self.page_allocator.deserialize_page_delta(page_delta);
+    /// representation. Fails without modifying the page map if the
+    /// representation is malformed.
+    pub fn deserialize_delta(
+        &mut self,
+        page_delta: PageDeltaSerialization,
+    ) -> Result<(), PersistenceError> {
+        let page_delta = self.page_allocator.deserialize_page_delta(page_delta)?;
         self.apply(page_delta);
+        Ok(())
     }
 
     /// Modifies this page map by adding the given dirty pages to it.
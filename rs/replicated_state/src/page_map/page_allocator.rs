@@ -1,14 +1,18 @@
-use ic_sys::{PageBytes, PageIndex};
+use ic_sys::{PageBytes, PageIndex, PAGE_SIZE};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fmt::Debug,
+    ops::Range,
     sync::atomic::{AtomicUsize, Ordering},
     sync::Arc,
 };
 mod page_bytes;
 
 // Exported publicly for benchmarking.
-pub use heap::{HeapBasedPage, HeapBasedPageAllocator};
+#[cfg(feature = "access_stats")]
+pub use heap::PageAccessStats;
+pub use heap::{AllocatorSnapshot, HeapBasedPage, HeapBasedPageAllocator};
 
 mod heap;
 mod mmap;
@@ -124,11 +128,11 @@ impl<A: PageAllocatorInner> PageAllocator<A> {
     }
 
     /// Creates a page-delta from the given serialization-friendly
-    /// representation.
+    /// representation. Fails if the representation is malformed.
     pub(super) fn deserialize_page_delta(
         &self,
         page_delta: PageDeltaSerialization,
-    ) -> Vec<(PageIndex, Page<A::PageInner>)> {
+    ) -> Result<Vec<(PageIndex, Page<A::PageInner>)>, PageDeltaError> {
         A::deserialize_page_delta(&self.0, page_delta)
     }
 }
@@ -139,6 +143,20 @@ pub trait PageInner: Debug {
 
     fn contents(&self) -> &PageBytes;
 
+    /// Returns the bytes of the page in the given range. This spares hot read
+    /// paths that only need a part of the page from indexing the full
+    /// contents.
+    ///
+    /// Panics if the range exceeds the page.
+    fn contents_range(&self, range: Range<usize>) -> &[u8] {
+        assert!(
+            range.start <= range.end && range.end <= PAGE_SIZE,
+            "Range {:?} exceeds the page",
+            range
+        );
+        &self.contents()[range]
+    }
+
     fn copy_from_slice(&mut self, offset: usize, slice: &[u8]);
 }
 
@@ -158,18 +176,163 @@ pub trait PageAllocatorInner: Debug + Default {
     /// See the comments of the corresponding method in `PageAllocator`.
     fn serialize(&self) -> PageAllocatorSerialization;
 
+    /// Returns the estimated number of bytes of memory held by the pages of
+    /// this allocator, including the bookkeeping overhead per page. This is
+    /// meant for memory metrics, not for exact accounting.
+    fn memory_footprint(&self) -> usize;
+
     /// See the comments of the corresponding method in `PageAllocator`.
     fn serialize_page_delta<'a, I>(&'a self, page_delta: I) -> PageDeltaSerialization
     where
         I: IntoIterator<Item = (PageIndex, &'a Page<Self::PageInner>)>;
 
+    /// Same as `serialize_page_delta()`, but only includes the pages whose
+    /// index falls inside the given range. The pages are filtered before their
+    /// contents are copied.
+    fn serialize_page_delta_range<'a, I>(
+        &'a self,
+        page_delta: I,
+        range: Range<PageIndex>,
+    ) -> PageDeltaSerialization
+    where
+        I: IntoIterator<Item = (PageIndex, &'a Page<Self::PageInner>)>,
+    {
+        self.serialize_page_delta(
+            page_delta
+                .into_iter()
+                .filter(|(index, _)| range.contains(index)),
+        )
+    }
+
     /// See the comments of the corresponding method in `PageAllocator`.
     fn deserialize_page_delta(
         page_allocator: &Arc<Self>,
         page_delta: PageDeltaSerialization,
-    ) -> Vec<(PageIndex, Page<Self::PageInner>)>;
+    ) -> Result<Vec<(PageIndex, Page<Self::PageInner>)>, PageDeltaError>;
+
+    /// Applies the given writes, each consisting of a page index, an offset
+    /// within the page and the bytes to write, to the given pages in one call.
+    /// This allows writing a buffer that spans multiple pages at once. The
+    /// pages must be sorted by page index.
+    ///
+    /// Fails without applying any write if a write exceeds the bounds of its
+    /// page, if its page is not in `pages`, or if its page is shared, i.e. not
+    /// privately owned.
+    fn copy_from_slices(
+        &self,
+        pages: &mut [(PageIndex, Page<Self::PageInner>)],
+        writes: &[(PageIndex, usize, &[u8])],
+    ) -> Result<(), PageWriteError> {
+        let mut positions = Vec::with_capacity(writes.len());
+        for (index, offset, slice) in writes {
+            if offset + slice.len() > PAGE_SIZE {
+                return Err(PageWriteError::OutOfBounds {
+                    index: *index,
+                    offset: *offset,
+                    len: slice.len(),
+                });
+            }
+            let position = pages
+                .binary_search_by_key(index, |(page_index, _)| *page_index)
+                .map_err(|_| PageWriteError::MissingPage(*index))?;
+            if Arc::get_mut(&mut (pages[position].1).0).is_none() {
+                return Err(PageWriteError::SharedPage(*index));
+            }
+            positions.push(position);
+        }
+        for (position, (index, offset, slice)) in positions.into_iter().zip(writes) {
+            Arc::get_mut(&mut (pages[position].1).0)
+                .ok_or(PageWriteError::SharedPage(*index))?
+                .copy_from_slice(*offset, slice);
+        }
+        Ok(())
+    }
+}
+
+/// The reasons why `PageAllocatorInner::copy_from_slices()` rejects writes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PageWriteError {
+    /// The write exceeds the bounds of its page.
+    OutOfBounds {
+        index: PageIndex,
+        offset: usize,
+        len: usize,
+    },
+    /// The page of the write is not among the given pages.
+    MissingPage(PageIndex),
+    /// The page of the write is shared, i.e. not privately owned.
+    SharedPage(PageIndex),
+}
+
+impl std::error::Error for PageWriteError {}
+
+impl std::fmt::Display for PageWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageWriteError::OutOfBounds { index, offset, len } => write!(
+                f,
+                "Write of {} bytes at offset {} exceeds page {}",
+                len, offset, index
+            ),
+            PageWriteError::MissingPage(index) => write!(f, "Write to missing page {}", index),
+            PageWriteError::SharedPage(index) => write!(f, "Write to shared page {}", index),
+        }
+    }
 }
 
+/// The reasons why a serialized page-delta cannot be deserialized.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PageDeltaError {
+    /// A page of a deduplicated page-delta refers to contents that do not
+    /// exist.
+    InvalidContentsPosition {
+        index: PageIndex,
+        position: u32,
+        contents_len: usize,
+    },
+    /// An mmap-based page-delta was deserialized by an allocator that has no
+    /// access to the backing file of its pages.
+    MissingBackingFile,
+    /// A page of an mmap-based page-delta could not be read from the backing
+    /// file.
+    UnreadablePage {
+        index: PageIndex,
+        file_offset: FileOffset,
+        internal_error: String,
+    },
+}
+
+impl std::error::Error for PageDeltaError {}
+
+impl std::fmt::Display for PageDeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageDeltaError::InvalidContentsPosition {
+                index,
+                position,
+                contents_len,
+            } => write!(
+                f,
+                "Page {} refers to contents {} of a page-delta with {} contents",
+                index, position, contents_len
+            ),
+            PageDeltaError::MissingBackingFile => {
+                write!(f, "Mmap-based page-delta without its backing file")
+            }
+            PageDeltaError::UnreadablePage {
+                index,
+                file_offset,
+                internal_error,
+            } => write!(
+                f,
+                "Failed to read page {} at offset {} of the backing file: {}",
+                index, file_offset, internal_error
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 struct PageCounter(AtomicUsize);
 
 impl PageCounter {
@@ -189,8 +352,24 @@ impl PageCounter {
         self.dec_by(1);
     }
 
+    // Saturates at zero, so that a bug that drops a page twice cannot turn the
+    // counter into a huge bogus value. A `fetch_sub()` followed by a correction
+    // would expose the wrapped value to concurrent readers, hence the
+    // compare-and-swap loop. It only retries if the counter changes in the
+    // meantime, which `drop_pages()` makes rare by decrementing once per batch.
     fn dec_by(&self, count: usize) {
-        self.0.fetch_sub(count, Ordering::Relaxed);
+        let previous = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(current.saturating_sub(count))
+            })
+            .unwrap();
+        debug_assert!(
+            previous >= count,
+            "Page counter underflow: {} - {}",
+            previous,
+            count
+        );
     }
 
     fn get(&self) -> usize {
@@ -225,6 +404,10 @@ pub struct PageSerialization {
     pub bytes: PageBytes,
 }
 
+/// Serialization-friendly representation of the contents of a page.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PageBytesSerialization(#[serde(with = "page_bytes")] pub PageBytes);
+
 /// Information for validating page contents.
 ///
 /// If the page contains only zeros, then both fields are zeros.  Otherwise,
@@ -265,6 +448,9 @@ pub struct MmapPageSerialization {
 /// so the three cases here correspond to the three cases in `PageAllocator`:
 /// - `Heap`: the pages are allocated on the Rust heap and can be sent to
 ///   another process only by copying the bytes.
+/// - `HeapDeduplicated`: same as `Heap`, but the distinct page contents are
+///   stored once in `contents` and each page refers to its contents by their
+///   position in `contents`. See `HeapBasedPageAllocator::with_deduplication()`.
 /// - `Mmap`: the pages are backed by the file owned by the page allocator. Each
 ///   page is represented by its offset in the file. The length of the file is
 ///   sent along to simplify deserialization. It is guaranteed that the file
@@ -272,6 +458,10 @@ pub struct MmapPageSerialization {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PageDeltaSerialization {
     Heap(Vec<PageSerialization>),
+    HeapDeduplicated {
+        contents: Vec<PageBytesSerialization>,
+        pages: Vec<(PageIndex, u32)>,
+    },
     Mmap {
         file_len: FileOffset,
         pages: Vec<MmapPageSerialization>,
@@ -282,9 +472,69 @@ impl PageDeltaSerialization {
     pub fn is_empty(&self) -> bool {
         match self {
             Self::Heap(pages) => pages.is_empty(),
+            Self::HeapDeduplicated { pages, .. } => pages.is_empty(),
             Self::Mmap { file_len, pages } => *file_len == 0 && pages.is_empty(),
         }
     }
+
+    // Returns the index and contents of every page, or `None` for mmap-based
+    // page-deltas, as the contents of their pages are not serialized.
+    fn heap_pages(&self) -> Option<Result<Vec<(PageIndex, &PageBytes)>, PageDeltaError>> {
+        match self {
+            Self::Heap(pages) => Some(Ok(pages
+                .iter()
+                .map(|page| (page.index, &page.bytes))
+                .collect())),
+            Self::HeapDeduplicated { contents, pages } => Some(
+                pages
+                    .iter()
+                    .map(|(index, position)| deduplicated_page(contents, *index, *position))
+                    .collect(),
+            ),
+            Self::Mmap { .. } => None,
+        }
+    }
+}
+
+// Returns the contents that a page of a deduplicated page-delta refers to.
+fn deduplicated_page(
+    contents: &[PageBytesSerialization],
+    index: PageIndex,
+    position: u32,
+) -> Result<(PageIndex, &PageBytes), PageDeltaError> {
+    contents
+        .get(position as usize)
+        .map(|bytes| (index, &bytes.0))
+        .ok_or(PageDeltaError::InvalidContentsPosition {
+            index,
+            position,
+            contents_len: contents.len(),
+        })
+}
+
+/// Returns the indices of the pages of `new` that are absent from `old` or
+/// whose contents differ from the ones in `old`, in the order of `new`. This
+/// allows writing only the pages that changed between two checkpoints.
+///
+/// Both page-deltas must be heap-based, i.e., `PageDeltaSerialization::Heap`
+/// or `PageDeltaSerialization::HeapDeduplicated`, as the contents of mmap-based
+/// pages are not part of their serialization. Fails if a page-delta is
+/// malformed.
+pub fn diff_page_deltas(
+    old: &PageDeltaSerialization,
+    new: &PageDeltaSerialization,
+) -> Result<Vec<PageIndex>, PageDeltaError> {
+    match (old.heap_pages(), new.heap_pages()) {
+        (Some(old), Some(new)) => {
+            let old: HashMap<PageIndex, &PageBytes> = old?.into_iter().collect();
+            Ok(new?
+                .into_iter()
+                .filter(|(index, bytes)| old.get(index) != Some(bytes))
+                .map(|(index, _)| index)
+                .collect())
+        }
+        _ => panic!("Only heap-based page-deltas can be diffed."),
+    }
 }
 
 #[cfg(test)]
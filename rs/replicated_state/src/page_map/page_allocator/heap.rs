@@ -1,24 +1,109 @@
 use super::{
-    Page, PageAllocatorInner, PageAllocatorSerialization, PageDeltaSerialization, PageInner,
+    deduplicated_page, MmapPageSerialization, Page, PageAllocatorInner, PageAllocatorSerialization,
+    PageBytesSerialization, PageCounter, PageDeltaError, PageDeltaSerialization, PageInner,
     PageSerialization, ALLOCATED_PAGES,
 };
-use ic_sys::{PageBytes, PageIndex};
-use std::sync::Arc;
+use ic_sys::{PageBytes, PageIndex, PAGE_SIZE};
+use lazy_static::lazy_static;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    mem::size_of,
+    os::unix::{fs::FileExt, io::FromRawFd},
+    sync::Arc,
+};
+
+#[cfg(any(feature = "access_stats", feature = "secure-erase"))]
+use std::sync::atomic::Ordering;
+
+lazy_static! {
+    // The size of an empty bincode-encoded `PageDeltaSerialization::Heap`.
+    static ref SERIALIZED_PAGE_DELTA_HEADER_SIZE: usize =
+        serialized_size(&PageDeltaSerialization::Heap(vec![]));
+
+    // The size that every page adds to a bincode-encoded
+    // `PageDeltaSerialization::Heap`.
+    static ref SERIALIZED_PAGE_SIZE: usize =
+        serialized_size(&PageDeltaSerialization::Heap(vec![PageSerialization {
+            index: PageIndex::new(0),
+            bytes: [0; PAGE_SIZE],
+        }])) - *SERIALIZED_PAGE_DELTA_HEADER_SIZE;
+}
+
+// Returns the size of the given page-delta when encoded with bincode, as in the
+// messages to and from the sandbox.
+fn serialized_size(page_delta: &PageDeltaSerialization) -> usize {
+    bincode::serialized_size(page_delta).expect("Failed to compute the size of a page-delta")
+        as usize
+}
+
+// The memory taken by a page in an `Arc`: the page and the strong and weak
+// reference counts.
+const HEAP_PAGE_FOOTPRINT: usize = size_of::<HeapBasedPage>() + 2 * size_of::<usize>();
+
+/// The number of reads and writes of the pages of an allocator.
+#[cfg(feature = "access_stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageAccessStats {
+    /// The number of calls of `contents()`.
+    pub reads: u64,
+    /// The number of calls of `copy_from_slice()`.
+    pub writes: u64,
+}
+
+#[cfg(feature = "access_stats")]
+#[derive(Debug, Default)]
+struct AccessCounters {
+    reads: std::sync::atomic::AtomicU64,
+    writes: std::sync::atomic::AtomicU64,
+}
 
 // A memory page allocated on the Rust heap.
 #[derive(Debug)]
-pub struct HeapBasedPage(PageBytes);
+pub struct HeapBasedPage {
+    bytes: PageBytes,
+    // The access counters of the allocator that allocated this page.
+    #[cfg(feature = "access_stats")]
+    access_counters: Arc<AccessCounters>,
+    // Set by `drop_pages()`, which decrements the allocated pages counter once
+    // for all pages it releases instead of on every drop.
+    released_in_batch: bool,
+}
 
 impl HeapBasedPage {
-    fn new(contents: &PageBytes) -> Self {
+    // Creates a page of `page_allocator` with the given contents.
+    fn new(contents: &PageBytes, page_allocator: &HeapBasedPageAllocator) -> Self {
         ALLOCATED_PAGES.inc();
-        Self(*contents)
+        page_allocator.allocated_pages.inc();
+        Self {
+            bytes: *contents,
+            #[cfg(feature = "access_stats")]
+            access_counters: Arc::clone(&page_allocator.access_counters),
+            released_in_batch: false,
+        }
+    }
+
+    // Overwrites the bytes of the page with zeros, so that the contents of
+    // canister memory do not linger in freed heap memory.
+    #[cfg(feature = "secure-erase")]
+    fn erase(&mut self) {
+        for byte in self.bytes.iter_mut() {
+            // SAFETY: `byte` is a valid, aligned reference. The volatile write
+            // keeps the compiler from eliding the writes to memory that is
+            // about to be freed.
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+        std::sync::atomic::compiler_fence(Ordering::SeqCst);
     }
 }
 
 impl Drop for HeapBasedPage {
     fn drop(&mut self) {
-        ALLOCATED_PAGES.dec();
+        #[cfg(feature = "secure-erase")]
+        self.erase();
+        if !self.released_in_batch {
+            ALLOCATED_PAGES.dec();
+        }
     }
 }
 
@@ -26,33 +111,241 @@ impl PageInner for HeapBasedPage {
     type PageAllocatorInner = HeapBasedPageAllocator;
 
     fn contents(&self) -> &PageBytes {
-        &self.0
+        #[cfg(feature = "access_stats")]
+        self.access_counters.reads.fetch_add(1, Ordering::Relaxed);
+        &self.bytes
     }
 
     fn copy_from_slice(&mut self, offset: usize, slice: &[u8]) {
-        (self.0[offset..offset + slice.len()]).copy_from_slice(slice);
+        #[cfg(feature = "access_stats")]
+        self.access_counters.writes.fetch_add(1, Ordering::Relaxed);
+        (self.bytes[offset..offset + slice.len()]).copy_from_slice(slice);
     }
 }
 
 // A trivial allocator that delegates to the default
 // Rust heap allocator.
 #[derive(Debug, Default)]
-pub struct HeapBasedPageAllocator {}
+pub struct HeapBasedPageAllocator {
+    // The backing file of the mmap-based allocator this allocator was
+    // deserialized from, if any. Page-deltas serialized by that allocator
+    // refer to pages in this file, which are copied to the heap. Like a
+    // deserialized mmap-based allocator, this allocator owns the received file
+    // descriptor.
+    mmap_file: Option<File>,
+    // The number of pages allocated by this allocator that were not released
+    // with `drop_pages()`. Pages do not refer back to their allocator, so pages
+    // dropped otherwise are still counted.
+    allocated_pages: PageCounter,
+    // Whether page-deltas are serialized with deduplicated contents. See
+    // `with_deduplication()`.
+    deduplicate_page_deltas: bool,
+    // The reads and writes of the pages allocated by this allocator.
+    #[cfg(feature = "access_stats")]
+    access_counters: Arc<AccessCounters>,
+}
+
+/// The pages of a `HeapBasedPageAllocator` captured by `snapshot()`. The pages
+/// are shared with their owners and kept alive by the snapshot.
+#[derive(Clone, Debug, Default)]
+pub struct AllocatorSnapshot {
+    pages: BTreeMap<PageIndex, Arc<HeapBasedPage>>,
+}
+
+impl HeapBasedPageAllocator {
+    /// Creates an allocator that serializes page-deltas as
+    /// `PageDeltaSerialization::HeapDeduplicated`, which stores pages with
+    /// identical contents only once. This shrinks page-deltas with repeated
+    /// contents, e.g., zeroed pages, at the cost of hashing the contents of
+    /// every page.
+    pub fn with_deduplication() -> Self {
+        Self {
+            deduplicate_page_deltas: true,
+            ..Default::default()
+        }
+    }
+
+    /// Captures the given pages, e.g., the current pages of a page map, so
+    /// that tests can roll back to them with `restore()`. The snapshot shares
+    /// the pages instead of copying them, so it is cheap to take.
+    pub fn snapshot<'a, I>(&self, pages: I) -> AllocatorSnapshot
+    where
+        I: IntoIterator<Item = (PageIndex, &'a Page<HeapBasedPage>)>,
+    {
+        let pages = pages
+            .into_iter()
+            .map(|(index, page)| (index, Arc::clone(&page.0)))
+            .collect();
+        AllocatorSnapshot { pages }
+    }
+
+    /// Returns the pages of `snapshot` in the order of their indices, so that
+    /// the caller can replace its pages with them.
+    pub fn restore(&self, snapshot: AllocatorSnapshot) -> Vec<(PageIndex, Page<HeapBasedPage>)> {
+        snapshot
+            .pages
+            .into_iter()
+            .map(|(index, page)| (index, Page(page)))
+            .collect()
+    }
+
+    /// Returns the number of reads and writes of the pages allocated by this
+    /// allocator so far, including the pages that are no longer alive.
+    #[cfg(feature = "access_stats")]
+    pub fn access_stats(&self) -> PageAccessStats {
+        PageAccessStats {
+            reads: self.access_counters.reads.load(Ordering::Relaxed),
+            writes: self.access_counters.writes.load(Ordering::Relaxed),
+        }
+    }
+
+    // Allocates a page with the given contents on the heap.
+    fn new_page(&self, contents: &PageBytes) -> Page<HeapBasedPage> {
+        Page(Arc::new(HeapBasedPage::new(contents, self)))
+    }
+
+    // Copies the given pages of the mmap-based allocator this allocator was
+    // deserialized from to the heap.
+    fn copy_mmap_pages(
+        &self,
+        pages: Vec<MmapPageSerialization>,
+    ) -> Result<Vec<(PageIndex, Page<HeapBasedPage>)>, PageDeltaError> {
+        let file = self
+            .mmap_file
+            .as_ref()
+            .ok_or(PageDeltaError::MissingBackingFile)?;
+        let mut copied = Vec::with_capacity(pages.len());
+        for page in pages {
+            let mut bytes = [0u8; PAGE_SIZE];
+            if let Err(err) = file.read_exact_at(&mut bytes, page.file_offset as u64) {
+                // Release the pages copied so far, so that the allocator no
+                // longer counts them.
+                self.drop_pages(copied.into_iter().map(|(_, page)| page));
+                return Err(PageDeltaError::UnreadablePage {
+                    index: page.page_index,
+                    file_offset: page.file_offset,
+                    internal_error: err.to_string(),
+                });
+            }
+            copied.push((page.page_index, self.new_page(&bytes)));
+        }
+        Ok(copied)
+    }
+
+    /// Same as `deserialize_page_delta()`, but reuses the pages of `existing`
+    /// whose contents are identical to the serialized contents. Only the
+    /// changed pages are allocated, which speeds up repeated checkpoint loads.
+    pub fn deserialize_page_delta_reusing(
+        &self,
+        page_delta: PageDeltaSerialization,
+        existing: &HashMap<PageIndex, Page<HeapBasedPage>>,
+    ) -> Result<Vec<(PageIndex, Page<HeapBasedPage>)>, PageDeltaError> {
+        let reuse_or_allocate = |index, bytes: &PageBytes| match existing.get(&index) {
+            Some(existing_page) if existing_page.0.contents() == bytes => {
+                (index, Page(Arc::clone(&existing_page.0)))
+            }
+            _ => (index, self.new_page(bytes)),
+        };
+        let pages = match page_delta {
+            PageDeltaSerialization::Heap(page_delta) => page_delta
+                .into_iter()
+                .map(|page| reuse_or_allocate(page.index, &page.bytes))
+                .collect(),
+            PageDeltaSerialization::HeapDeduplicated { contents, pages } => {
+                // Resolve all pages first, so that nothing is allocated for a
+                // malformed page-delta.
+                let pages: Vec<_> = pages
+                    .iter()
+                    .map(|(index, position)| deduplicated_page(&contents, *index, *position))
+                    .collect::<Result<_, _>>()?;
+                pages
+                    .into_iter()
+                    .map(|(index, bytes)| reuse_or_allocate(index, bytes))
+                    .collect()
+            }
+            PageDeltaSerialization::Mmap { pages, .. } => return self.copy_mmap_pages(pages),
+        };
+        Ok(pages)
+    }
+
+    // Serializes the given page-delta with the contents of pages with
+    // identical contents stored only once. The contents of each page are
+    // hashed to find the pages with identical contents.
+    fn serialize_page_delta_deduplicated<'a, I>(&'a self, page_delta: I) -> PageDeltaSerialization
+    where
+        I: IntoIterator<Item = (PageIndex, &'a Page<HeapBasedPage>)>,
+    {
+        let mut positions: HashMap<&PageBytes, u32> = HashMap::new();
+        let mut contents = Vec::new();
+        let pages = page_delta
+            .into_iter()
+            .map(|(index, page)| {
+                let bytes = page.0.contents();
+                let position = *positions.entry(bytes).or_insert_with(|| {
+                    contents.push(PageBytesSerialization(*bytes));
+                    (contents.len() - 1) as u32
+                });
+                (index, position)
+            })
+            .collect();
+        PageDeltaSerialization::HeapDeduplicated { contents, pages }
+    }
+
+    /// Same as `allocate()`, but takes the pages from an iterator, so that
+    /// callers do not need to collect them into a slice first. The returned
+    /// vector is allocated once with the length of the iterator.
+    pub fn allocate_from_iter<'a, I>(&self, pages: I) -> Vec<(PageIndex, Page<HeapBasedPage>)>
+    where
+        I: ExactSizeIterator<Item = (PageIndex, &'a PageBytes)>,
+    {
+        let mut allocated = Vec::with_capacity(pages.len());
+        allocated.extend(pages.map(|(page_index, contents)| (page_index, self.new_page(contents))));
+        allocated
+    }
+
+    /// Returns the number of bytes that the serialization of the given
+    /// page-delta takes when it is sent to another process, without actually
+    /// serializing it. This allows callers to budget IO upfront.
+    pub fn estimate_serialized_size<'a, I>(&'a self, page_delta: I) -> usize
+    where
+        I: IntoIterator<Item = (PageIndex, &'a Page<HeapBasedPage>)>,
+    {
+        *SERIALIZED_PAGE_DELTA_HEADER_SIZE + page_delta.into_iter().count() * *SERIALIZED_PAGE_SIZE
+    }
+
+    /// Drops the given pages, which must have been allocated by this
+    /// allocator, and decrements the page counters once by the number of
+    /// released pages instead of once per page, which avoids contention on the
+    /// counters when many pages are released together. Pages that are still
+    /// shared elsewhere are not released. Returns the number of released pages.
+    pub fn drop_pages<I>(&self, pages: I) -> usize
+    where
+        I: IntoIterator<Item = Page<HeapBasedPage>>,
+    {
+        let mut released = 0;
+        for mut page in pages {
+            // The page is dropped in place at the end of the iteration if this
+            // is its last reference.
+            if let Some(inner) = Arc::get_mut(&mut page.0) {
+                inner.released_in_batch = true;
+                released += 1;
+            }
+        }
+        ALLOCATED_PAGES.dec_by(released);
+        self.allocated_pages.dec_by(released);
+        released
+    }
+}
 
 impl PageAllocatorInner for HeapBasedPageAllocator {
     type PageInner = HeapBasedPage;
 
     // See the comments of the corresponding method in `PageAllocator`.
     fn allocate(
-        _page_allocator: &Arc<HeapBasedPageAllocator>,
+        page_allocator: &Arc<HeapBasedPageAllocator>,
         pages: &[(PageIndex, &PageBytes)],
     ) -> Vec<(PageIndex, Page<Self::PageInner>)> {
-        pages
-            .iter()
-            .map(|(page_index, contents)| {
-                (*page_index, Page(Arc::new(HeapBasedPage::new(*contents))))
-            })
-            .collect()
+        page_allocator.allocate_from_iter(pages.iter().copied())
     }
 
     // See the comments of the corresponding method in `PageAllocator`.
@@ -60,14 +353,24 @@ impl PageAllocatorInner for HeapBasedPageAllocator {
         PageAllocatorSerialization::Heap
     }
 
+    // The pages of this allocator that were not released with `drop_pages()`
+    // and the reference counts of their `Arc`s.
+    fn memory_footprint(&self) -> usize {
+        self.allocated_pages.get() * HEAP_PAGE_FOOTPRINT
+    }
+
     // See the comments of the corresponding method in `PageAllocator`.
     fn deserialize(serialized_page_allocator: PageAllocatorSerialization) -> Self {
         match serialized_page_allocator {
             PageAllocatorSerialization::Heap => Default::default(),
-            PageAllocatorSerialization::Mmap(..) => {
-                // This is really unreachable. See `serialize()`.
-                unreachable!("Unexpected serialization of heap-based page allocator.");
-            }
+            // A checkpoint of an mmap-based allocator, e.g., during a
+            // migration between hosts. Its pages are copied on deserialization.
+            PageAllocatorSerialization::Mmap(file_descriptor) => Self {
+                // SAFETY: The file descriptor is valid and not owned by anything
+                // else in this process.
+                mmap_file: Some(unsafe { File::from_raw_fd(file_descriptor.fd) }),
+                ..Default::default()
+            },
         }
     }
 
@@ -76,6 +379,9 @@ impl PageAllocatorInner for HeapBasedPageAllocator {
     where
         I: IntoIterator<Item = (PageIndex, &'a Page<Self::PageInner>)>,
     {
+        if self.deduplicate_page_deltas {
+            return self.serialize_page_delta_deduplicated(page_delta);
+        }
         // Copy the contents of all pages.
         let pages = page_delta
             .into_iter()
@@ -89,19 +395,28 @@ impl PageAllocatorInner for HeapBasedPageAllocator {
 
     // See the comments of the corresponding method in `PageAllocator`.
     fn deserialize_page_delta(
-        _page_allocator: &Arc<HeapBasedPageAllocator>,
+        page_allocator: &Arc<HeapBasedPageAllocator>,
         page_delta: PageDeltaSerialization,
-    ) -> Vec<(PageIndex, Page<Self::PageInner>)> {
+    ) -> Result<Vec<(PageIndex, Page<Self::PageInner>)>, PageDeltaError> {
         // Allocate all pages on the Rust heap.
         match page_delta {
-            PageDeltaSerialization::Heap(page_delta) => page_delta
+            PageDeltaSerialization::Heap(page_delta) => Ok(page_delta
                 .into_iter()
-                .map(|page| (page.index, Page(Arc::new(HeapBasedPage(page.bytes)))))
-                .collect(),
-            PageDeltaSerialization::Mmap { .. } => {
-                // This is really unreachable. See `serialize_page_delta()`.
-                unreachable!("Unexpected serialization of page-delta in HeapBasedPageAllocator.");
+                .map(|page| (page.index, page_allocator.new_page(&page.bytes)))
+                .collect()),
+            PageDeltaSerialization::HeapDeduplicated { contents, pages } => {
+                // Resolve all pages first, so that nothing is allocated for a
+                // malformed page-delta.
+                let pages: Vec<_> = pages
+                    .iter()
+                    .map(|(index, position)| deduplicated_page(&contents, *index, *position))
+                    .collect::<Result<_, _>>()?;
+                Ok(page_allocator.allocate_from_iter(pages.into_iter()))
             }
+            PageDeltaSerialization::Mmap { pages, .. } => page_allocator.copy_mmap_pages(pages),
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use super::HeapBasedPageAllocator;
+use crate::page_map::page_allocator::{
+    tests::assert_page_delta_roundtrip, MmapBasedPageAllocator, Page, PageAllocatorInner,
+    PageAllocatorSerialization, PageBytesSerialization, PageDeltaError, PageDeltaSerialization,
+    PageInner, PageSerialization,
+};
+use crate::page_map::FileDescriptor;
+use ic_sys::{PageIndex, PAGE_SIZE};
+use nix::unistd::dup;
+
+#[test]
+fn test_serialize_page_delta_range() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let contents: Vec<_> = (0..5u8).map(|i| [i; PAGE_SIZE]).collect();
+    let pages: Vec<_> = contents
+        .iter()
+        .enumerate()
+        .map(|(i, contents)| (PageIndex::new(i as u64), contents))
+        .collect();
+    let pages = HeapBasedPageAllocator::allocate(&page_allocator, &pages);
+    let serialized = page_allocator.serialize_page_delta_range(
+        pages.iter().map(|(index, page)| (*index, page)),
+        PageIndex::new(1)..PageIndex::new(4),
+    );
+    match serialized {
+        PageDeltaSerialization::Heap(pages) => {
+            let indices: Vec<_> = pages.iter().map(|page| page.index).collect();
+            assert_eq!(
+                indices,
+                vec![PageIndex::new(1), PageIndex::new(2), PageIndex::new(3)]
+            );
+            for page in pages.iter() {
+                assert_eq!(page.bytes, [page.index.get() as u8; PAGE_SIZE]);
+            }
+        }
+        _ => panic!("Unexpected serialization of page-delta in HeapBasedPageAllocator."),
+    }
+}
+
+#[test]
+fn test_deserialize_page_delta_reusing_keeps_unchanged_pages() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let pages = HeapBasedPageAllocator::allocate(
+        &page_allocator,
+        &[
+            (PageIndex::new(0), &[0u8; PAGE_SIZE]),
+            (PageIndex::new(1), &[1u8; PAGE_SIZE]),
+        ],
+    );
+    let existing: HashMap<_, _> = pages.into_iter().collect();
+
+    let mut changed = [1u8; PAGE_SIZE];
+    changed[0] = 42;
+    let page_delta = PageDeltaSerialization::Heap(vec![
+        PageSerialization {
+            index: PageIndex::new(0),
+            bytes: [0u8; PAGE_SIZE],
+        },
+        PageSerialization {
+            index: PageIndex::new(1),
+            bytes: changed,
+        },
+        PageSerialization {
+            index: PageIndex::new(2),
+            bytes: [2u8; PAGE_SIZE],
+        },
+    ]);
+    let deserialized = page_allocator
+        .deserialize_page_delta_reusing(page_delta, &existing)
+        .unwrap();
+
+    assert_eq!(deserialized.len(), 3);
+    assert!(Arc::ptr_eq(
+        &deserialized[0].1 .0,
+        &existing[&PageIndex::new(0)].0
+    ));
+    assert!(!Arc::ptr_eq(
+        &deserialized[1].1 .0,
+        &existing[&PageIndex::new(1)].0
+    ));
+    assert_eq!(deserialized[1].1 .0.contents(), &changed);
+    assert_eq!(deserialized[2].0, PageIndex::new(2));
+    assert_eq!(deserialized[2].1 .0.contents(), &[2u8; PAGE_SIZE]);
+}
+
+#[test]
+fn test_estimate_serialized_size() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let contents: Vec<_> = (0..10u8).map(|i| [i; PAGE_SIZE]).collect();
+    let pages: Vec<_> = contents
+        .iter()
+        .enumerate()
+        .map(|(i, contents)| (PageIndex::new(i as u64 * 3), contents))
+        .collect();
+    let pages = HeapBasedPageAllocator::allocate(&page_allocator, &pages);
+
+    let estimate =
+        page_allocator.estimate_serialized_size(pages.iter().map(|(index, page)| (*index, page)));
+    let serialized =
+        page_allocator.serialize_page_delta(pages.iter().map(|(index, page)| (*index, page)));
+    let actual = bincode::serialize(&serialized).unwrap().len();
+    assert_eq!(estimate, actual);
+
+    let empty = page_allocator.estimate_serialized_size(std::iter::empty());
+    let actual = bincode::serialize(&PageDeltaSerialization::Heap(vec![]))
+        .unwrap()
+        .len();
+    assert_eq!(empty, actual);
+}
+
+#[test]
+fn test_drop_pages_releases_batch() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let contents = [1u8; PAGE_SIZE];
+    let pages: Vec<_> = (0..1000).map(|i| (PageIndex::new(i), &contents)).collect();
+    let pages = HeapBasedPageAllocator::allocate(&page_allocator, &pages);
+    assert_eq!(page_allocator.allocated_pages.get(), 1000);
+
+    // A page that is still shared is not released.
+    let shared = Page(Arc::clone(&pages[0].1 .0));
+    let released = page_allocator.drop_pages(pages.into_iter().map(|(_, page)| page));
+    assert_eq!(released, 999);
+    assert_eq!(page_allocator.allocated_pages.get(), 1);
+
+    assert_eq!(page_allocator.drop_pages(vec![shared]), 1);
+    assert_eq!(page_allocator.allocated_pages.get(), 0);
+}
+
+#[test]
+fn test_deserialize_mmap_page_delta() {
+    let mmap_allocator = Arc::new(MmapBasedPageAllocator::default());
+    let contents: Vec<_> = (0..3u8).map(|i| [i + 1; PAGE_SIZE]).collect();
+    let pages: Vec<_> = contents
+        .iter()
+        .enumerate()
+        .map(|(i, contents)| (PageIndex::new(i as u64 * 10), contents))
+        .collect();
+    let pages = MmapBasedPageAllocator::allocate(&mmap_allocator, &pages);
+    // The deserialized allocator owns the file descriptor, as if it had been
+    // sent to another process.
+    let serialized_allocator = match mmap_allocator.serialize() {
+        PageAllocatorSerialization::Mmap(file_descriptor) => {
+            PageAllocatorSerialization::Mmap(FileDescriptor {
+                fd: dup(file_descriptor.fd).unwrap(),
+            })
+        }
+        PageAllocatorSerialization::Heap => panic!("Unexpected serialization of mmap allocator."),
+    };
+    let page_delta =
+        mmap_allocator.serialize_page_delta(pages.iter().map(|(index, page)| (*index, page)));
+    assert!(matches!(page_delta, PageDeltaSerialization::Mmap { .. }));
+
+    let heap_allocator = Arc::new(HeapBasedPageAllocator::deserialize(serialized_allocator));
+    let heap_pages =
+        HeapBasedPageAllocator::deserialize_page_delta(&heap_allocator, page_delta).unwrap();
+
+    assert_eq!(heap_pages.len(), 3);
+    for ((index, heap_page), (mmap_index, mmap_page)) in heap_pages.iter().zip(pages.iter()) {
+        assert_eq!(index, mmap_index);
+        assert_eq!(heap_page.0.contents(), mmap_page.0.contents());
+    }
+}
+
+#[test]
+fn test_deserialize_unreadable_mmap_page_delta() {
+    let mmap_allocator = Arc::new(MmapBasedPageAllocator::default());
+    let contents = [1u8; PAGE_SIZE];
+    let pages = MmapBasedPageAllocator::allocate(
+        &mmap_allocator,
+        &[
+            (PageIndex::new(0), &contents),
+            (PageIndex::new(1), &contents),
+        ],
+    );
+    let page_delta =
+        mmap_allocator.serialize_page_delta(pages.iter().map(|(index, page)| (*index, page)));
+
+    let heap_allocator = Arc::new(HeapBasedPageAllocator::default());
+    assert_eq!(
+        HeapBasedPageAllocator::deserialize_page_delta(&heap_allocator, page_delta.clone())
+            .unwrap_err(),
+        PageDeltaError::MissingBackingFile
+    );
+
+    // The second page lies beyond the end of the backing file.
+    let page_delta = match page_delta {
+        PageDeltaSerialization::Mmap {
+            file_len,
+            mut pages,
+        } => {
+            pages[1].file_offset = file_len;
+            PageDeltaSerialization::Mmap { file_len, pages }
+        }
+        _ => panic!("Unexpected serialization of mmap page-delta."),
+    };
+    let heap_allocator = match mmap_allocator.serialize() {
+        PageAllocatorSerialization::Mmap(file_descriptor) => Arc::new(
+            HeapBasedPageAllocator::deserialize(PageAllocatorSerialization::Mmap(FileDescriptor {
+                fd: dup(file_descriptor.fd).unwrap(),
+            })),
+        ),
+        PageAllocatorSerialization::Heap => panic!("Unexpected serialization of mmap allocator."),
+    };
+    match HeapBasedPageAllocator::deserialize_page_delta(&heap_allocator, page_delta) {
+        Err(PageDeltaError::UnreadablePage { index, .. }) => assert_eq!(index, PageIndex::new(1)),
+        result => panic!("Unexpected result of deserialization: {:?}", result),
+    }
+    assert_eq!(heap_allocator.memory_footprint(), 0);
+}
+
+#[test]
+fn test_memory_footprint() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    assert_eq!(page_allocator.memory_footprint(), 0);
+
+    let contents = [1u8; PAGE_SIZE];
+    let pages: Vec<_> = (0..100).map(|i| (PageIndex::new(i), &contents)).collect();
+    let mut pages = HeapBasedPageAllocator::allocate(&page_allocator, &pages);
+    let footprint = page_allocator.memory_footprint();
+    assert!(footprint >= 100 * PAGE_SIZE);
+    // The overhead of the `Arc` and the bookkeeping is small compared to a page.
+    assert!(footprint <= 100 * (PAGE_SIZE + 64));
+
+    // Other allocators do not count.
+    let other_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let _other_pages =
+        HeapBasedPageAllocator::allocate(&other_allocator, &[(PageIndex::new(0), &contents)]);
+    assert_eq!(page_allocator.memory_footprint(), footprint);
+
+    let released = pages.split_off(50);
+    page_allocator.drop_pages(released.into_iter().map(|(_, page)| page));
+    assert_eq!(page_allocator.memory_footprint(), footprint / 2);
+    page_allocator.drop_pages(pages.into_iter().map(|(_, page)| page));
+    assert_eq!(page_allocator.memory_footprint(), 0);
+}
+
+#[test]
+fn test_allocate_from_iter() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let contents: Vec<_> = (0..3u8).map(|i| [i; PAGE_SIZE]).collect();
+    // The pages are produced on the fly instead of being collected first.
+    let pages = page_allocator
+        .allocate_from_iter((0..300usize).map(|i| (PageIndex::new(i as u64), &contents[i % 3])));
+    assert_eq!(pages.len(), 300);
+    assert_eq!(pages.capacity(), 300);
+    for (index, page) in pages.iter() {
+        assert_eq!(page.0.contents(), &contents[index.get() as usize % 3]);
+    }
+    assert_eq!(
+        page_allocator.memory_footprint(),
+        300 * super::HEAP_PAGE_FOOTPRINT
+    );
+}
+
+#[test]
+fn test_page_delta_roundtrip() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let mut contents: Vec<_> = (0..4u8).map(|i| [i; PAGE_SIZE]).collect();
+    // A page whose bytes all differ from their neighbours.
+    for (i, byte) in contents[3].iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    let pages: Vec<_> = contents
+        .iter()
+        .enumerate()
+        .map(|(i, contents)| (PageIndex::new(2 * i as u64), contents))
+        .collect();
+    assert_page_delta_roundtrip(&page_allocator, &pages);
+    assert_page_delta_roundtrip(&page_allocator, &[]);
+}
+
+#[test]
+fn test_serialize_page_delta_deduplicated() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::with_deduplication());
+    let zeros = [0u8; PAGE_SIZE];
+    let ones = [1u8; PAGE_SIZE];
+    let pages: Vec<_> = (0..10)
+        .map(|i| (PageIndex::new(i), if i == 3 { &ones } else { &zeros }))
+        .collect();
+    let pages = HeapBasedPageAllocator::allocate(&page_allocator, &pages);
+
+    let deduplicated =
+        page_allocator.serialize_page_delta(pages.iter().map(|(index, page)| (*index, page)));
+    match &deduplicated {
+        PageDeltaSerialization::HeapDeduplicated { contents, pages } => {
+            assert_eq!(contents.len(), 2);
+            assert_eq!(pages.len(), 10);
+        }
+        _ => panic!("Unexpected serialization of deduplicated page-delta."),
+    }
+    let plain = HeapBasedPageAllocator::default()
+        .serialize_page_delta(pages.iter().map(|(index, page)| (*index, page)));
+    let deduplicated_size = bincode::serialize(&deduplicated).unwrap().len();
+    let plain_size = bincode::serialize(&plain).unwrap().len();
+    assert!(deduplicated_size < 3 * PAGE_SIZE);
+    assert!(plain_size > 10 * PAGE_SIZE);
+
+    let deserialized = HeapBasedPageAllocator::deserialize_page_delta(
+        &page_allocator,
+        bincode::deserialize(&bincode::serialize(&deduplicated).unwrap()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(deserialized.len(), 10);
+    for ((index, page), (original_index, original_page)) in deserialized.iter().zip(pages.iter()) {
+        assert_eq!(index, original_index);
+        assert_eq!(page.0.contents(), original_page.0.contents());
+    }
+
+    let existing: HashMap<_, _> = pages.into_iter().collect();
+    let reused = page_allocator
+        .deserialize_page_delta_reusing(deduplicated, &existing)
+        .unwrap();
+    assert!(reused
+        .iter()
+        .all(|(index, page)| Arc::ptr_eq(&page.0, &existing[index].0)));
+}
+
+#[test]
+fn test_deserialize_malformed_deduplicated_page_delta() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let malformed = || PageDeltaSerialization::HeapDeduplicated {
+        contents: vec![PageBytesSerialization([0u8; PAGE_SIZE])],
+        pages: vec![(PageIndex::new(0), 0), (PageIndex::new(1), 1)],
+    };
+    let expected = PageDeltaError::InvalidContentsPosition {
+        index: PageIndex::new(1),
+        position: 1,
+        contents_len: 1,
+    };
+
+    assert_eq!(
+        HeapBasedPageAllocator::deserialize_page_delta(&page_allocator, malformed()).unwrap_err(),
+        expected
+    );
+    assert_eq!(
+        page_allocator
+            .deserialize_page_delta_reusing(malformed(), &HashMap::new())
+            .unwrap_err(),
+        expected
+    );
+    assert_eq!(page_allocator.memory_footprint(), 0);
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let contents: Vec<_> = (0..3u8).map(|i| [i; PAGE_SIZE]).collect();
+    let pages: Vec<_> = contents
+        .iter()
+        .enumerate()
+        .map(|(i, contents)| (PageIndex::new(i as u64), contents))
+        .collect();
+    let original = HeapBasedPageAllocator::allocate(&page_allocator, &pages);
+    let snapshot = page_allocator.snapshot(original.iter().map(|(index, page)| (*index, page)));
+    let original_page = Arc::clone(&original[1].1 .0);
+
+    // Overwrite a page and add another one.
+    let mut current: BTreeMap<_, _> = original.into_iter().collect();
+    current.extend(HeapBasedPageAllocator::allocate(
+        &page_allocator,
+        &[
+            (PageIndex::new(1), &[42u8; PAGE_SIZE]),
+            (PageIndex::new(5), &[5u8; PAGE_SIZE]),
+        ],
+    ));
+    assert_eq!(current.len(), 4);
+    assert_eq!(current[&PageIndex::new(1)].0.contents(), &[42u8; PAGE_SIZE]);
+    let mutated_page = Arc::downgrade(&current[&PageIndex::new(1)].0);
+
+    // The original pages are dropped, but the snapshot keeps them alive.
+    drop(current);
+    let restored = page_allocator.restore(snapshot);
+    assert_eq!(restored.len(), 3);
+    for (i, (index, page)) in restored.iter().enumerate() {
+        assert_eq!(*index, PageIndex::new(i as u64));
+        assert_eq!(page.0.contents(), &contents[i]);
+    }
+    // The snapshot shares the pages instead of copying them.
+    assert!(Arc::ptr_eq(&restored[1].1 .0, &original_page));
+    // The pages allocated after the snapshot are freed once dropped.
+    assert!(mutated_page.upgrade().is_none());
+}
+
+#[cfg(feature = "access_stats")]
+#[test]
+fn test_access_stats() {
+    use super::PageAccessStats;
+
+    let page_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let mut pages = HeapBasedPageAllocator::allocate(
+        &page_allocator,
+        &[
+            (PageIndex::new(0), &[0u8; PAGE_SIZE]),
+            (PageIndex::new(1), &[1u8; PAGE_SIZE]),
+        ],
+    );
+    assert_eq!(page_allocator.access_stats(), PageAccessStats::default());
+
+    for _ in 0..3 {
+        assert_eq!(pages[0].1 .0.contents()[0], 0);
+    }
+    let page = Arc::get_mut(&mut pages[1].1 .0).unwrap();
+    page.copy_from_slice(0, &[2u8; 16]);
+    page.copy_from_slice(16, &[3u8; 16]);
+    assert_eq!(page.contents()[16], 3);
+    assert_eq!(
+        page_allocator.access_stats(),
+        PageAccessStats {
+            reads: 4,
+            writes: 2
+        }
+    );
+
+    // Pages of other allocators do not count, dropped pages still do.
+    let other_allocator = Arc::new(HeapBasedPageAllocator::default());
+    let other_pages = HeapBasedPageAllocator::allocate(
+        &other_allocator,
+        &[(PageIndex::new(0), &[0u8; PAGE_SIZE])],
+    );
+    other_pages[0].1 .0.contents();
+    drop(pages);
+    assert_eq!(page_allocator.access_stats().reads, 4);
+    assert_eq!(other_allocator.access_stats().reads, 1);
+}
+
+#[cfg(feature = "secure-erase")]
+#[test]
+fn test_dropped_page_is_erased() {
+    use super::HeapBasedPage;
+    use std::mem::ManuallyDrop;
+
+    let page_allocator = HeapBasedPageAllocator::default();
+    let mut page = ManuallyDrop::new(HeapBasedPage::new(&[42u8; PAGE_SIZE], &page_allocator));
+    let bytes = page.bytes.as_ptr();
+    // SAFETY: The page is dropped exactly once. Its storage stays owned by this
+    // test, so its bytes can still be read afterwards.
+    let erased = unsafe {
+        ManuallyDrop::drop(&mut page);
+        std::slice::from_raw_parts(bytes, PAGE_SIZE)
+    };
+    assert!(erased.iter().all(|byte| *byte == 0));
+}
@@ -1,7 +1,7 @@
 use crate::page_map::{FileDescriptor, FileOffset};
 
 use super::{
-    MmapPageSerialization, Page, PageAllocatorInner, PageAllocatorSerialization,
+    MmapPageSerialization, Page, PageAllocatorInner, PageAllocatorSerialization, PageDeltaError,
     PageDeltaSerialization, PageInner, PageValidation, ALLOCATED_PAGES,
 };
 use cvt::{cvt, cvt_r};
@@ -191,6 +191,14 @@ impl PageAllocatorInner for MmapBasedPageAllocator {
         }
     }
 
+    // The backing file is mapped in full, so its length is the footprint.
+    fn memory_footprint(&self) -> usize {
+        match self.0.lock().unwrap().as_ref() {
+            Some(core) => core.file_len as usize,
+            None => 0,
+        }
+    }
+
     // See the comments of the corresponding method in `PageAllocator`.
     fn serialize_page_delta<'a, I>(&'a self, page_delta: I) -> PageDeltaSerialization
     where
@@ -216,7 +224,7 @@ impl PageAllocatorInner for MmapBasedPageAllocator {
     fn deserialize_page_delta(
         page_allocator: &Arc<MmapBasedPageAllocator>,
         page_delta: PageDeltaSerialization,
-    ) -> Vec<(PageIndex, Page<Self::PageInner>)> {
+    ) -> Result<Vec<(PageIndex, Page<Self::PageInner>)>, PageDeltaError> {
         match page_delta {
             PageDeltaSerialization::Mmap { file_len, pages } => {
                 let mut guard = page_allocator.0.lock().unwrap();
@@ -228,15 +236,15 @@ impl PageAllocatorInner for MmapBasedPageAllocator {
                 // File offsets of all pages are smaller than `file_len`, which means
                 // that the precondition of `deserialize_page()` is fulfilled after
                 // the call to `grow_for_deserialization(file_len)`.
-                pages
+                Ok(pages
                     .into_iter()
                     .map(|ser| {
                         let page = core.deserialize_page(&ser, page_allocator);
                         (ser.page_index, Page(Arc::new(page)))
                     })
-                    .collect()
+                    .collect())
             }
-            PageDeltaSerialization::Heap(_) => {
+            PageDeltaSerialization::Heap(_) | PageDeltaSerialization::HeapDeduplicated { .. } => {
                 // This is really unreachable. See `serialize_page_delta()`.
                 unreachable!("Unexpected serialization of page-delta in MmapBasedPageAllocator");
             }
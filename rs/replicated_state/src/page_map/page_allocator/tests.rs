@@ -1,5 +1,33 @@
-use super::{PageAllocator, PageSerialization};
-use ic_sys::{PageIndex, PAGE_SIZE};
+use super::{
+    diff_page_deltas, DefaultPageAllocatorImpl, PageAllocator, PageAllocatorInner,
+    PageBytesSerialization, PageCounter, PageDeltaSerialization, PageInner, PageSerialization,
+    PageWriteError,
+};
+use ic_sys::{PageBytes, PageIndex, PAGE_SIZE};
+use std::sync::Arc;
+
+/// Allocates `pages` with `page_allocator`, serializes them as a page-delta,
+/// deserializes it back and asserts that every page has the same index and
+/// contents as before. Shared by the tests of all page allocator backends.
+pub(super) fn assert_page_delta_roundtrip<A: PageAllocatorInner>(
+    page_allocator: &Arc<A>,
+    pages: &[(PageIndex, &PageBytes)],
+) {
+    let allocated = A::allocate(page_allocator, pages);
+    let serialized =
+        page_allocator.serialize_page_delta(allocated.iter().map(|(index, page)| (*index, page)));
+    let deserialized = A::deserialize_page_delta(page_allocator, serialized).unwrap();
+    assert_eq!(deserialized.len(), pages.len());
+    for ((index, contents), (deserialized_index, page)) in pages.iter().zip(deserialized.iter()) {
+        assert_eq!(index, deserialized_index);
+        assert_eq!(
+            &page.0.contents()[..],
+            &contents[..],
+            "Contents of page {} differ after the round trip",
+            index
+        );
+    }
+}
 
 #[test]
 fn test_page_allocation() {
@@ -41,3 +69,185 @@ fn test_page_serialization() {
     assert_eq!(page.index, result.index);
     assert_eq!(page.bytes, result.bytes);
 }
+
+#[test]
+fn test_diff_page_deltas() {
+    let page = |index, byte| PageSerialization {
+        index: PageIndex::new(index),
+        bytes: [byte; PAGE_SIZE],
+    };
+    let old = PageDeltaSerialization::Heap(vec![page(0, 0), page(1, 1), page(2, 2), page(3, 3)]);
+    // Pages 1 and 2 overlap, with page 2 changed. Pages 5 and 6 are disjoint.
+    let new = PageDeltaSerialization::Heap(vec![page(1, 1), page(2, 42), page(5, 5), page(6, 6)]);
+
+    assert_eq!(
+        diff_page_deltas(&old, &new).unwrap(),
+        vec![PageIndex::new(2), PageIndex::new(5), PageIndex::new(6)]
+    );
+    assert!(diff_page_deltas(&new, &new).unwrap().is_empty());
+    assert!(
+        diff_page_deltas(&new, &PageDeltaSerialization::Heap(vec![]))
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_diff_page_deltas_deduplicated() {
+    let old = PageDeltaSerialization::Heap(vec![
+        PageSerialization {
+            index: PageIndex::new(0),
+            bytes: [0; PAGE_SIZE],
+        },
+        PageSerialization {
+            index: PageIndex::new(1),
+            bytes: [1; PAGE_SIZE],
+        },
+    ]);
+    // Pages 0 and 2 share their contents, page 1 changed.
+    let new = PageDeltaSerialization::HeapDeduplicated {
+        contents: vec![
+            PageBytesSerialization([0; PAGE_SIZE]),
+            PageBytesSerialization([42; PAGE_SIZE]),
+        ],
+        pages: vec![
+            (PageIndex::new(0), 0),
+            (PageIndex::new(1), 1),
+            (PageIndex::new(2), 0),
+        ],
+    };
+
+    assert_eq!(
+        diff_page_deltas(&old, &new).unwrap(),
+        vec![PageIndex::new(1), PageIndex::new(2)]
+    );
+    assert_eq!(
+        diff_page_deltas(&new, &old).unwrap(),
+        vec![PageIndex::new(1)]
+    );
+    assert!(diff_page_deltas(&new, &new).unwrap().is_empty());
+}
+
+#[test]
+fn test_copy_from_slices_spanning_three_pages() {
+    let page_allocator = Arc::new(DefaultPageAllocatorImpl::default());
+    let mut pages = DefaultPageAllocatorImpl::allocate(
+        &page_allocator,
+        &[
+            (PageIndex::new(7), &[0u8; PAGE_SIZE]),
+            (PageIndex::new(8), &[0u8; PAGE_SIZE]),
+            (PageIndex::new(9), &[0u8; PAGE_SIZE]),
+        ],
+    );
+    // The buffer starts in the middle of the first page and ends in the
+    // middle of the third one.
+    let offset = 100;
+    let buffer: Vec<u8> = (0..2 * PAGE_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+    let (first, rest) = buffer.split_at(PAGE_SIZE - offset);
+    let (second, third) = rest.split_at(PAGE_SIZE);
+    page_allocator
+        .copy_from_slices(
+            &mut pages,
+            &[
+                (PageIndex::new(7), offset, first),
+                (PageIndex::new(8), 0, second),
+                (PageIndex::new(9), 0, third),
+            ],
+        )
+        .unwrap();
+
+    let mut memory = Vec::new();
+    for (_, page) in pages.iter() {
+        memory.extend_from_slice(page.contents());
+    }
+    assert!(memory[..offset].iter().all(|byte| *byte == 0));
+    assert_eq!(&memory[offset..offset + buffer.len()], &buffer[..]);
+    assert!(memory[offset + buffer.len()..]
+        .iter()
+        .all(|byte| *byte == 0));
+}
+
+#[test]
+fn test_copy_from_slices_rejects_invalid_writes() {
+    let page_allocator = Arc::new(DefaultPageAllocatorImpl::default());
+    let mut pages = DefaultPageAllocatorImpl::allocate(
+        &page_allocator,
+        &[
+            (PageIndex::new(0), &[0u8; PAGE_SIZE]),
+            (PageIndex::new(1), &[0u8; PAGE_SIZE]),
+        ],
+    );
+    let valid = (PageIndex::new(0), 0, &[1u8, 2][..]);
+
+    assert_eq!(
+        page_allocator.copy_from_slices(
+            &mut pages,
+            &[valid, (PageIndex::new(1), PAGE_SIZE - 1, &[1, 2])]
+        ),
+        Err(PageWriteError::OutOfBounds {
+            index: PageIndex::new(1),
+            offset: PAGE_SIZE - 1,
+            len: 2,
+        })
+    );
+    assert_eq!(
+        page_allocator.copy_from_slices(&mut pages, &[valid, (PageIndex::new(2), 0, &[1])]),
+        Err(PageWriteError::MissingPage(PageIndex::new(2)))
+    );
+    let shared = pages[1].1.clone();
+    assert_eq!(
+        page_allocator.copy_from_slices(&mut pages, &[valid, (PageIndex::new(1), 0, &[1])]),
+        Err(PageWriteError::SharedPage(PageIndex::new(1)))
+    );
+    drop(shared);
+
+    // No write is applied if any of them is rejected.
+    assert!(pages[0].1.contents().iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn test_contents_range() {
+    let page_allocator: PageAllocator = PageAllocator::default();
+    let mut contents = [0u8; PAGE_SIZE];
+    for (i, byte) in contents.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let pages = page_allocator.allocate(&[(PageIndex::new(0), &contents)]);
+    let page = &(pages[0].1).0;
+    assert_eq!(page.contents_range(10..14), &[10, 11, 12, 13]);
+    assert_eq!(page.contents_range(0..PAGE_SIZE), &contents[..]);
+    assert_eq!(
+        page.contents_range(PAGE_SIZE - 2..PAGE_SIZE),
+        &contents[PAGE_SIZE - 2..]
+    );
+    assert!(page.contents_range(7..7).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "exceeds the page")]
+fn test_contents_range_checks_bounds() {
+    let page_allocator: PageAllocator = PageAllocator::default();
+    let pages = page_allocator.allocate(&[(PageIndex::new(0), &[0u8; PAGE_SIZE])]);
+    (pages[0].1).0.contents_range(PAGE_SIZE - 1..PAGE_SIZE + 1);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Page counter underflow")]
+fn test_page_counter_underflow_panics_in_debug_builds() {
+    let counter = PageCounter::new();
+    counter.inc();
+    counter.dec_by(2);
+}
+
+#[test]
+#[cfg(not(debug_assertions))]
+fn test_page_counter_saturates() {
+    let counter = PageCounter::new();
+    counter.inc_by(2);
+    counter.dec();
+    counter.dec_by(2);
+    assert_eq!(counter.get(), 0);
+    counter.inc();
+    assert_eq!(counter.get(), 1);
+}
@@ -223,7 +223,7 @@ fn serialize_page_map() {
     // The sandbox process sends the dirty pages to the replica process.
     let page_delta =
         sandbox.serialize_delta(&[PageIndex::new(1), PageIndex::new(3), PageIndex::new(7)]);
-    replica.deserialize_delta(page_delta);
+    replica.deserialize_delta(page_delta).unwrap();
     // The page deltas must be in sync.
     assert_equal_page_maps(&replica, &sandbox);
 }
@@ -76,6 +76,7 @@ impl Options {
                         ssh_key_pairs: vec![],
                         // this interface is deprecated and that's why we use a fake id here
                         node_id: NodeId::from(PrincipalId::new_node_test_id(0)),
+                        client: Default::default(),
                     })
                 }
                 Some(endpoints)
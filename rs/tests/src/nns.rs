@@ -171,12 +171,11 @@ pub trait NnsExt {
     /// A list of all nodes that were registered with the initial registry (i.e.
     /// at bootstrap).
     fn initial_node_ids(&self, handle: &IcHandle) -> Vec<NodeId> {
-        let ic_prep_dir = handle
-            .ic_prep_working_dir
-            .as_ref()
+        let local_store_path = handle
+            .local_store_path()
             .expect("ic_prep_working_dir is not set.");
 
-        LocalStoreImpl::new(ic_prep_dir.registry_local_store_path().as_path())
+        LocalStoreImpl::new(local_store_path.as_path())
             .get_changelog_since_version(RegistryVersion::from(0))
             .expect("Could not fetch changelog.")
             .iter()
@@ -252,11 +251,7 @@ impl NnsExt for ic_fondue::pot::Context {
         version: ReplicaVersion,
     ) {
         // get the subnet id of the subnet with index subnet index
-        let reg_path = handle
-            .ic_prep_working_dir
-            .as_ref()
-            .unwrap()
-            .registry_local_store_path();
+        let reg_path = handle.local_store_path().unwrap();
         let local_store = LocalStoreImpl::new(&reg_path);
         let changelog = local_store
             .get_changelog_since_version(RegistryVersion::from(0))